@@ -1,30 +1,292 @@
 // app.rs
 use crate::{tui, event, config, stream::StreamManager, stream::ServerId, stream::NetEvent};
+use crate::sasl::SaslConfig;
+use crate::caps::CapState;
+use crate::isupport::ISupport;
+use crate::casemap::{CaseMapping, IrcKey};
+use crate::ctcp::{self, Ctcp, ReplyLimiter};
+use crate::logger::Logger;
+use crate::scrollback::{Scrollback, DEFAULT_SCROLLBACK_LINES};
+use crate::search::Search;
+use crate::layout::{Node, PaneLayout};
+use crate::highlight::{HighlightRules, Mention, MAX_MENTIONS};
+use crate::message::{IrcMessage, Prefix};
+use crate::chatline::{ChatLine, LineKind, DEFAULT_HISTORY_LINES, DEFAULT_TIMESTAMP_FORMAT};
+use unicode_width::UnicodeWidthStr;
 use ratatui::DefaultTerminal;
 use ratatui::widgets::ListState;
+use ratatui::layout::Rect;
 use ratatui::text::Span;
 use crossterm::terminal::{self};
 use tokio::sync::mpsc;
 use std::io::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use chrono::{DateTime, Local, Utc};
 use textwrap::{wrap, Options};
-use regex::Regex;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::collections::btree_map::Entry;
 
+// What happened in a buffer since it was last shown, ordered so the most important level wins
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Activity {
+    #[default]
+    None,
+    Events,
+    Messages,
+    Highlight,
+    Private,
+}
+
+pub const DEFAULT_SIDEBAR_WIDTH: u16 = 20;
+pub const DEFAULT_NICKLIST_WIDTH: u16 = 16;
+// The sidebar and nick list hide themselves when the chat would get narrower than this
+const MIN_CHAT_WIDTH: u16 = 40;
+
+// When a buffer was opened, numbers buffers in join order so a new channel does not renumber the ones before it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OpenOrder(usize);
+
+impl OpenOrder {
+    // Taken once, where a buffer is created
+    pub fn next() -> OpenOrder {
+        static NEXT: AtomicUsize = AtomicUsize::new(1);
+        OpenOrder(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 #[derive(Default)]
 pub struct ChannelData {
-    pub chat_list: Vec<(String, String)>,
-    pub user_list: Vec<String>,
+    pub chat_list: Vec<ChatLine>,
+    pub members: BTreeMap<IrcKey, Member>,
     pub chat_pos: usize,
-    pub notification: bool,
+    pub activity: Activity,
+    // Messages that arrived while the buffer was not shown
+    pub unread: usize,
+    pub opened: OpenOrder,
+    // Channel modes, k/l keep their parameter, CHANMODES type A modes like bans live in lists
+    pub modes: BTreeMap<char, Option<String>>,
+    pub lists: BTreeMap<char, Vec<String>>,
+    pub topic: Option<String>,
+    // Who set the topic and when, from TOPIC or 333
+    pub topic_setter: Option<String>,
+    pub topic_time: Option<DateTime<Local>>,
+    // Kicked or parted by the server, the buffer stays open until /part
+    pub parted: bool,
+    // A CHATHISTORY request is on its way, and the server had nothing older last time
+    pub history_pending: bool,
+    pub history_end: bool,
+    // Limit of the CHATHISTORY BEFORE on its way, a shorter answer is the start of the history
+    pub history_before: Option<usize>,
+}
+
+impl ChannelData {
+    // An empty buffer, numbered after the ones opened before it
+    pub fn new() -> ChannelData {
+        ChannelData { opened: OpenOrder::next(), ..Default::default() }
+    }
+
+    pub fn mark_read(&mut self) {
+        self.activity = Activity::None;
+        self.unread = 0;
+    }
+
+    // Leaving the buffer, the one marker line moves under the last line seen
+    pub fn set_read_marker(&mut self) {
+        self.chat_list.retain(|l| l.kind != LineKind::Marker);
+        if !self.chat_list.is_empty() {
+            self.chat_list.push(ChatLine::read_marker());
+        }
+    }
+
+    // Back in the buffer with nothing new under the marker
+    pub fn drop_stale_marker(&mut self) {
+        if self.chat_list.last().is_some_and(|l| l.kind == LineKind::Marker) {
+            self.chat_list.pop();
+        }
+    }
+
+    // Apply one parsed MODE change that is not a prefix mode
+    pub fn apply_mode(&mut self, isupport: &ISupport, adding: bool, mode: char, param: Option<String>) {
+        if isupport.chanmodes[0].contains(mode) {
+            let list = self.lists.entry(mode).or_default();
+            if let Some(mask) = param {
+                list.retain(|m| *m != mask);
+                if adding {
+                    list.push(mask);
+                }
+            }
+        } else if adding {
+            self.modes.insert(mode, param);
+        } else {
+            self.modes.remove(&mode);
+        }
+    }
+
+    // Lines of a chathistory batch go in after the last line not newer than them, lines already shown (same msgid) are skipped.
+    // The read marker has no time of its own, and restored scrollback is not always in order, so the list is scanned
+    pub fn merge_history(&mut self, lines: Vec<ChatLine>) {
+        for line in lines {
+            if line.msgid().is_some_and(|id| self.chat_list.iter().any(|l| l.msgid() == Some(id))) {
+                continue;
+            }
+            let pos = self.chat_list.iter().rposition(|l| l.kind != LineKind::Marker && l.timestamp <= line.timestamp).map_or(0, |i| i + 1);
+            self.chat_list.insert(pos, line);
+        }
+    }
+
+    // +ntl 50 for the chat block title
+    pub fn mode_string(&self) -> String {
+        if self.modes.is_empty() {
+            return String::new();
+        }
+        let flags: String = self.modes.keys().collect();
+        let params: Vec<&str> = self.modes.values().flatten().map(|p| p.as_str()).collect();
+        if params.is_empty() {
+            format!("+{}", flags)
+        } else {
+            format!("+{} {}", flags, params.join(" "))
+        }
+    }
+
+    // Highest rank first, then alphabetical
+    pub fn sorted_members(&self, isupport: &ISupport) -> Vec<&Member> {
+        let mut members: Vec<&Member> = self.members.values().collect();
+        members.sort_by_cached_key(|m| (m.rank(isupport), isupport.casemapping.fold(&m.nick)));
+        members
+    }
+}
+
+// One nick in a channel, the prefix modes are per channel, the rest is the same everywhere
+#[derive(Clone, Debug, Default)]
+pub struct Member {
+    pub nick: String,
+    pub modes: BTreeSet<char>,
+    pub away: bool,
+    pub account: Option<String>,
+    pub realname: Option<String>,
+    pub user: Option<String>,
+    pub host: Option<String>,
 }
 
-type ChannelName = String;
+impl Member {
+    pub fn new(nick: &str) -> Member {
+        Member { nick: nick.to_string(), ..Default::default() }
+    }
+
+    // Index of the highest prefix mode in PREFIX, members without one sort last
+    pub fn rank(&self, isupport: &ISupport) -> usize {
+        isupport.prefix.iter().position(|(mode, _)| self.modes.contains(mode)).unwrap_or(isupport.prefix.len())
+    }
+
+    // Symbol shown in front of the nick, @ for ops
+    pub fn symbol(&self, isupport: &ISupport) -> Option<char> {
+        isupport.prefix.get(self.rank(isupport)).map(|(_, symbol)| *symbol)
+    }
+}
+
+pub type ChannelName = IrcKey;
+
+// BATCH +ref chathistory <target>, lines are held until BATCH -ref
+#[derive(Debug, Default)]
+pub struct HistoryBatch {
+    pub target: String,
+    pub lines: Vec<ChatLine>,
+}
 
 #[derive(Default)]
 pub struct ServerData {
     pub channels: BTreeMap<ChannelName, ChannelData>,
     pub nick: String,
+    pub sasl: Option<SaslConfig>,
+    pub caps: CapState,
+    pub restore_nick: Option<String>,
+    pub join_keys: BTreeMap<ChannelName, String>,
+    // Channels typed in /join, their buffer is shown once the JOIN comes back
+    pub joining: BTreeSet<ChannelName>,
+    pub lag: Option<Duration>,
+    pub send_queue: usize,
+    // Our own user@host as the server sees it, from 001, 396, our JOIN or CHGHOST
+    pub source: Option<Prefix>,
+    pub isupport: ISupport,
+    // Last NETWORK the server sent, kept over a reconnect so logs stay in one place before 005 comes again
+    pub network: Option<String>,
+    // Open chathistory batches by reference
+    pub history: BTreeMap<String, HistoryBatch>,
+}
+
+impl ServerData {
+    // Map key for a channel or query name in this server's casemapping
+    pub fn key(&self, name: &str) -> ChannelName {
+        IrcKey::new(name, self.isupport.casemapping)
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&ChannelData> {
+        self.channels.get(&self.key(name))
+    }
+
+    pub fn channel_mut(&mut self, name: &str) -> Option<&mut ChannelData> {
+        let key = self.key(name);
+        self.channels.get_mut(&key)
+    }
+
+    // SASL config for this session, kept for the next one when the server does not offer its mechanism
+    pub fn session_sasl(&self) -> Option<&SaslConfig> {
+        self.sasl.as_ref().filter(|_| !self.caps.sasl_skipped)
+    }
+
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.isupport.casemapping.equal(nick, &self.nick)
+    }
+
+    // Update a nick in every channel it is in, for AWAY/ACCOUNT/CHGHOST/SETNAME
+    fn update_member(&mut self, nick: &str, mut update: impl FnMut(&mut Member)) {
+        let key = self.key(nick);
+        for channel in self.channels.values_mut() {
+            if let Some(member) = channel.members.get_mut(&key) {
+                update(member);
+            }
+        }
+    }
+
+    // CASEMAPPING changed, fold the existing keys again and merge buffers that now have the same name
+    fn rekey(&mut self) {
+        let mapping = self.isupport.casemapping;
+        for (name, data) in std::mem::take(&mut self.channels) {
+            let mut data = data;
+            data.members = std::mem::take(&mut data.members).into_values().map(|m| (IrcKey::new(&m.nick, mapping), m)).collect();
+            match self.channels.entry(IrcKey::new(name.as_str(), mapping)) {
+                Entry::Occupied(mut o) => o.get_mut().chat_list.extend(data.chat_list),
+                Entry::Vacant(v) => {
+                    v.insert(data);
+                }
+            }
+        }
+        self.join_keys = std::mem::take(&mut self.join_keys).into_iter().map(|(name, key)| (IrcKey::new(name.as_str(), mapping), key)).collect();
+        self.joining = std::mem::take(&mut self.joining).into_iter().map(|name| IrcKey::new(name.as_str(), mapping)).collect();
+    }
+
+    // Bytes left for the text of "<command> <target> :<text>" once the server adds our prefix
+    pub fn message_room(&self, command: &str, target: &str) -> usize {
+        //Unknown parts count as the longest the server could make them, USERLEN 10 and a 63 byte host
+        let (user, host) = match &self.source {
+            Some(source) => (source.user.as_ref().map_or(10, |u| u.len()), source.host.as_ref().map_or(63, |h| h.len())),
+            None => (10, 63),
+        };
+        let header = 1 + self.nick.len() + 1 + user + 1 + host + 1 + command.len() + 1 + target.len() + 2;
+        510usize.saturating_sub(header)
+    }
+
+    fn set_source(&mut self, user: Option<&str>, host: Option<&str>) {
+        let source = self.source.get_or_insert_with(Prefix::default);
+        source.nick = self.nick.clone();
+        if let Some(user) = user.filter(|u| !u.is_empty()) {
+            source.user = Some(user.to_string());
+        }
+        if let Some(host) = host.filter(|h| !h.is_empty()) {
+            source.host = Some(host.to_string());
+        }
+    }
 }
 
 #[derive(Debug, Default, PartialEq, Eq)]
@@ -36,6 +298,8 @@ pub enum Popup {
     Help,
     User,
     Channel,
+    Search,
+    Mentions,
 }
 
 #[derive(Default)]
@@ -62,13 +326,38 @@ pub struct App {
     pub prompt_pos: usize,
     pub list_pos: usize,
     pub menu_pos: usize,
-    pub split: (bool, String, String, String, String),
+    // Panes of the chat area, the focused one follows active_server and active_channel
+    pub layout: PaneLayout,
+    // Named layouts from the config and /layout save
+    pub layouts: BTreeMap<String, Node>,
     pub style_bg: (u8, u8, u8),
     pub style_fg: (u8, u8, u8),
     pub style_notif: (u8, u8, u8),
     pub style_highlight: (u8, u8, u8),
     pub style_txt: (u8, u8, u8),
     pub input_mode: Vec<Span<'static>>,
+    pub ctcp_limiter: ReplyLimiter,
+    // strftime format of the timestamp in front of every chat line
+    pub timestamp_format: String,
+    // Lines asked for with CHATHISTORY, 0 turns history off
+    pub history_lines: usize,
+    pub logger: Logger,
+    pub scrollback: Scrollback,
+    pub search: Search,
+    pub search_state: ListState,
+    pub highlights: HighlightRules,
+    // Recent highlighted lines of every server, oldest first
+    pub mentions: Vec<Mention>,
+    // Server and channel shown after the last event, to notice a switch
+    pub last_buffer: (String, String),
+    // Buffer tree left of the chat
+    pub sidebar: bool,
+    pub sidebar_width: u16,
+    pub sidebar_state: ListState,
+    // Members of the active channel right of the chat, scrolled on its own
+    pub nicklist: bool,
+    pub nicklist_width: u16,
+    pub nicklist_pos: usize,
 }
 
 impl App {
@@ -86,28 +375,35 @@ impl App {
         self.style_notif = (140, 255, 238);
         self.style_highlight = (238, 140, 255);
         self.style_txt = (255, 255, 255);
+        self.sidebar_width = DEFAULT_SIDEBAR_WIDTH;
+        self.nicklist_width = DEFAULT_NICKLIST_WIDTH;
+        self.timestamp_format = DEFAULT_TIMESTAMP_FORMAT.to_string();
+        self.history_lines = DEFAULT_HISTORY_LINES;
+        self.scrollback.lines = DEFAULT_SCROLLBACK_LINES;
 
         let system_server = self.server_list
             .entry("System".to_string())
             .or_default();
         system_server.nick = self.active_nick.clone();
         system_server.channels
-            .insert("Status".to_string(), ChannelData {
+            .insert(IrcKey::new("Status", CaseMapping::default()), ChannelData {
             chat_list: vec![
-                (String::from("System"),"    __           _           ___ _           _   ".to_string()),
-                (String::from("System"),"   /__\\_   _ ___| |_ _   _  / __\\ |__   __ _| |_ ".to_string()),
-                (String::from("System"),"  / \\// | | / __| __| | | |/ /  | '_ \\ / _` | __|".to_string()), 
-                (String::from("System")," / _  \\ |_| \\__ \\ |_| |_| / /___| | | | (_| | |_ ".to_string()), 
-                (String::from("System")," \\/ \\_/\\__,_|___/\\__|\\__, \\____/|_| |_|\\__,_|\\__|".to_string()), 
-                (String::from("System"),"                     |___/                       ".to_string())],
-            user_list: Vec::new(),
+                ChatLine::system("    __           _           ___ _           _   "),
+                ChatLine::system("   /__\\_   _ ___| |_ _   _  / __\\ |__   __ _| |_ "),
+                ChatLine::system("  / \\// | | / __| __| | | |/ /  | '_ \\ / _` | __|"), 
+                ChatLine::system(" / _  \\ |_| \\__ \\ |_| |_| / /___| | | | (_| | |_ "), 
+                ChatLine::system(" \\/ \\_/\\__,_|___/\\__|\\__, \\____/|_| |_|\\__,_|\\__|"), 
+                ChatLine::system("                     |___/                       ")],
+            members: BTreeMap::new(),
             chat_pos: 0,
-            notification: false,
+            opened: OpenOrder::next(),
+            ..Default::default()
         });
         system_server.nick = self.active_nick.clone();
 
         self.active_server = "System".to_string();
         self.active_channel = "Status".to_string();
+        self.last_buffer = (self.active_server.clone(), self.active_channel.clone());
         self.spark_data = [0,1,2,3,4,5,6,7,8,9,9,9,9,8,7,6,5,4,3,2,1,0].to_vec();
         let (nw, nh) = terminal::size().unwrap();
         self.w = nw;
@@ -116,6 +412,7 @@ impl App {
 
         config::read_theme(self);
         config::read_config(self);
+        self.scrollback.start();
         config::read_autojoin(self, &net_tx).await;
 
         // Spawn input handler
@@ -136,6 +433,7 @@ impl App {
                     break;
                 }
             }
+            self.buffer_switched();
 
         }
         Ok(())
@@ -147,265 +445,924 @@ impl App {
                 let bytes = line.clone().into_bytes();
                 self.spark_data = bytes.iter().map(|&b| b as u64).collect();
 
-                let mut value = line.split_whitespace();
-                let prefix = value.next();
-                let arg = value.next();
-                let command_pos = line.find(arg.unwrap_or(""));
-                let command = &line[command_pos.unwrap_or(0) as usize + arg.unwrap_or("").len()..];
-
-                match prefix {
-                    Some("PING") => {
-                        let pong_line = line.to_string().replace("PING", "PONG");
-                        self.stream_mgr.send_line(server_id.clone(), pong_line);
-                    },
-                    _ => {}
-                }
-                match arg {
-                    Some("001") => {
-                        //Welcome, parse autojoin channels
+                let Some(msg) = IrcMessage::parse(&line) else {
+                    return;
+                };
+                let nick = msg.nick().to_string();
+
+                match msg.command.as_str() {
+                    "PING" => {
+                        let pong = IrcMessage::new("PONG", msg.params.clone());
+                        self.stream_mgr.send_line(server_id.clone(), pong.to_string());
+                    }
+                    "AUTHENTICATE" => {
+                        //Server is ready for the SASL payload
+                        if msg.param(0) == "+" {
+                            if let Some(sasl) = self.server_list.get(&server_id).and_then(|s| s.session_sasl().cloned()) {
+                                for response in sasl.responses() {
+                                    self.stream_mgr.send_line(server_id.clone(), response);
+                                }
+                            }
+                        }
+                    }
+                    "001" => {
+                        //Welcome, rejoin channels after a reconnect and parse autojoin channels
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            server.caps.registered = true;
+                            server.caps.negotiating = false;
+                            server.nick = msg.param(0).to_string();
+                            //Most servers end the welcome text with nick!user@host
+                            let welcome = Prefix::parse(msg.trailing().rsplit(' ').next().unwrap_or(""));
+                            server.set_source(welcome.user.as_deref(), welcome.host.as_deref());
+                            if let Some(wanted) = server.restore_nick.take() {
+                                if !server.is_me(&wanted) {
+                                    self.stream_mgr.send_line(server_id.clone(), "NICK ".to_string() + &wanted);
+                                }
+                            }
+                            //Keyed channels first so the key list lines up, as many per JOIN as TARGMAX allows
+                            let mut chans: Vec<&ChannelName> = server.channels.iter()
+                                .filter(|(c, data)| server.isupport.is_channel(c.as_str()) && !data.parted)
+                                .map(|(c, _)| c)
+                                .collect();
+                            chans.sort_by_key(|c| !server.join_keys.contains_key(*c));
+                            for batch in chans.chunks(server.isupport.max_targets("JOIN").unwrap_or(10).max(1)) {
+                                let names: Vec<&str> = batch.iter().map(|c| c.as_str()).collect();
+                                let keys: Vec<&str> = batch.iter().filter_map(|c| server.join_keys.get(*c)).map(|k| k.as_str()).collect();
+                                self.stream_mgr.send_line(server_id.clone(), format!("JOIN {} {}", names.join(","), keys.join(",")).trim_end().to_string());
+                            }
+                            if self.active_server == server_id {
+                                self.active_nick = server.nick.clone();
+                            }
+                        }
                         config::autojoin_channel(self, server_id);
                     }
-                    Some("NOTICE") => {
-                        if prefix.unwrap_or("").to_uppercase().starts_with(":ALIS!") {
-                            let start_byte = line.find("#");
-                            let result = &line[start_byte.unwrap_or(0)..];
+                    "NOTICE" => {
+                        if nick.eq_ignore_ascii_case("ALIS") {
+                            let text = msg.trailing();
+                            let result = &text[text.find('#').unwrap_or(0)..];
                             self.list_pos = 0;
                             self.popup = Popup::List;
-                            if result.to_uppercase().contains("RETURNING MAXIMUM OF") {
-                                //Do not push
-                            } else if result.to_uppercase().contains("MAXIMUM CHANNEL OUTPUT REACHED"){
-                                //Do not push
-                            } else {
+                            let upper = result.to_uppercase();
+                            if !upper.contains("RETURNING MAXIMUM OF") && !upper.contains("MAXIMUM CHANNEL OUTPUT REACHED") {
                                 self.list_response.push(result.to_string());
                             }
+                        } else if let Some(ctcp) = Ctcp::parse(msg.trailing()) {
+                            //CTCP reply, our PING token gives the round trip
+                            let text = match ctcp::ping_rtt(&ctcp.args).filter(|_| ctcp.command == "PING") {
+                                Some(rtt) => format!("CTCP PING reply from {}: {:.3}s", nick, rtt.as_secs_f64()),
+                                None => format!("CTCP {} reply from {}: {}", ctcp.command, nick, ctcp.args),
+                            };
+                            self.chat_bounds(ChatLine::new(LineKind::Notice, "CTCP", &text).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                            if self.active_server == server_id && self.active_channel != "Status" {
+                                self.chat_copy(ChatLine::new(LineKind::Notice, "CTCP", &text).with_tags(&msg.tags), server_id.clone(), self.active_channel.clone());
+                            }
+                        } else if let Some(channel) = self.channel_target(&server_id, msg.param(0)) {
+                            //Channel notice, shown in the channel as -nick-
+                            self.chat_bounds(ChatLine::new(LineKind::Notice, &format!("-{}-", nick), msg.trailing()).with_tags(&msg.tags), server_id.clone(), channel);
                         } else {
-                            self.chat_bounds(line.to_string(), server_id.clone(), "Status".to_string(), arg.unwrap_or("").to_string());
+                            self.chat_bounds(ChatLine::new(LineKind::Notice, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                        }
+                    }
+                    "CAP" => {
+                        //HANDLE capability negotiation, CAP <target> <sub> [*] :caps
+                        let sub = msg.param(1).to_uppercase();
+                        let more = msg.params.len() > 3 && msg.param(2) == "*";
+                        let caps = msg.trailing();
+                        let mut send: Vec<String> = Vec::new();
+                        let mut info: Vec<String> = Vec::new();
+
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            match sub.as_str() {
+                                "LS" if server.caps.ls(caps, more) => {
+                                    if let Some(sasl) = &server.sasl {
+                                        let mechs = server.caps.value("sasl");
+                                        if mechs.is_none() {
+                                            info.push("Server does not support SASL".to_string());
+                                        } else if mechs.is_some_and(|m| !m.is_empty() && !m.split(',').any(|m| m == sasl.mechanism_name())) {
+                                            info.push(format!("Server does not support SASL {}", sasl.mechanism_name()));
+                                            server.caps.sasl_skipped = true;
+                                        }
+                                    }
+                                    send.extend(server.caps.request_lines(server.session_sasl().is_some()));
+                                }
+                                "NEW" => {
+                                    server.caps.new_caps(caps);
+                                    send.extend(server.caps.request_lines(false));
+                                }
+                                "DEL" => {
+                                    server.caps.del(caps);
+                                    info.push("Capabilities removed: ".to_string() + caps);
+                                }
+                                "ACK" => {
+                                    let added = server.caps.ack(caps);
+                                    if let Some(mechanism) = server.session_sasl().map(|s| s.mechanism_name()) {
+                                        if added.iter().any(|c| c == "sasl") && server.caps.negotiating {
+                                            server.caps.sasl_in_progress = true;
+                                            send.push("AUTHENTICATE ".to_string() + mechanism);
+                                        }
+                                    }
+                                    if !added.is_empty() {
+                                        info.push("Capabilities enabled: ".to_string() + &added.join(" "));
+                                    }
+                                }
+                                "NAK" => {
+                                    server.caps.nak();
+                                    info.push("Capabilities rejected: ".to_string() + caps);
+                                }
+                                _ => {}
+                            }
+                            if server.caps.end_ready() {
+                                send.push("CAP END".to_string());
+                            }
+                        }
+                        for line in send {
+                            self.stream_mgr.send_line(server_id.clone(), line);
+                        }
+                        for text in info {
+                            self.chat_bounds(ChatLine::new(LineKind::System, "CAP", &text).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
                         }
                     }
-                    Some("366") => {
+                    "900" | "903" | "904" | "905" | "902" | "906" => {
+                        //HANDLE SASL result, registration continues either way
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            if msg.command != "900" {
+                                server.caps.sasl_in_progress = false;
+                            }
+                            if server.caps.end_ready() {
+                                self.stream_mgr.send_line(server_id.clone(), "CAP END".to_string());
+                            }
+                        }
+                        self.chat_bounds(ChatLine::new(LineKind::System, "SASL", msg.trailing()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                    }
+                    "CHGHOST" => {
+                        //CHGHOST <user> <host>
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            if server.is_me(&nick) {
+                                server.set_source(Some(msg.param(0)), Some(msg.param(1)));
+                            }
+                            server.update_member(&nick, |m| {
+                                m.user = Some(msg.param(0).to_string());
+                                m.host = Some(msg.param(1).to_string());
+                            });
+                        }
+                    }
+                    "AWAY" => {
+                        //away-notify, AWAY :message when going away and no parameter when back
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            server.update_member(&nick, |m| m.away = !msg.params.is_empty());
+                        }
+                    }
+                    "ACCOUNT" => {
+                        //account-notify, * when logged out
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            let account = Some(msg.param(0).to_string()).filter(|a| a != "*");
+                            server.update_member(&nick, |m| m.account = account.clone());
+                        }
+                    }
+                    "SETNAME" => {
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            server.update_member(&nick, |m| m.realname = Some(msg.trailing().to_string()));
+                        }
+                    }
+                    "396" => {
+                        //396 <nick> <host> :is now your displayed host
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            server.set_source(None, Some(msg.param(1)));
+                        }
+                        self.chat_bounds(ChatLine::new(LineKind::System, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                    }
+                    "BATCH" => {
+                        //BATCH +ref chathistory <target> opens playback, BATCH -ref merges it, other batch types are hidden
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            let reference = msg.param(0);
+                            if let Some(id) = reference.strip_prefix('+') {
+                                if msg.param(1) == "chathistory" {
+                                    server.history.insert(id.to_string(), HistoryBatch { target: msg.param(2).to_string(), lines: Vec::new() });
+                                }
+                            } else if let Some(batch) = reference.strip_prefix('-').and_then(|id| server.history.remove(id)) {
+                                if let Some(channel) = server.channel_mut(&batch.target) {
+                                    channel.history_pending = false;
+                                    //Less than asked for before the oldest line means there is nothing older to ask for
+                                    if channel.history_before.take().is_some_and(|limit| batch.lines.len() < limit) {
+                                        channel.history_end = true;
+                                    }
+                                    channel.merge_history(batch.lines);
+                                }
+                            }
+                        }
+                    }
+                    "005" => {
+                        //RPL_ISUPPORT, server features
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            let mapping = server.isupport.casemapping;
+                            server.isupport.apply(&msg.params);
+                            if server.isupport.network.is_some() {
+                                server.network = server.isupport.network.clone();
+                            }
+                            if server.isupport.casemapping != mapping {
+                                server.rekey();
+                            }
+                        }
+                        self.chat_bounds(ChatLine::new(LineKind::System, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                    }
+                    "366" => {
                         //Hide Incomming Message
                     }
-                    Some("322") => {
-                        //HANDLE LIST COMMAND
+                    "322" => {
+                        //HANDLE LIST COMMAND, 322 <client> <channel> <users> :topic
                         self.list_pos = 0;
                         self.popup = Popup::List;
-                        let start_byte = line.find('#');
-                        let result = &line[start_byte.unwrap_or(0)..];
-                        self.list_response.push(result.to_string());
-                    }
-                    Some("331") => {
-                        //HANDLE no topic
-                        let start_byte = command.find('#');
-                        let end_byte = command.find(':');
-                        self.chat_bounds(command[end_byte.unwrap_or(0)+1..].to_string(), server_id.clone(), command[start_byte.unwrap_or(0)..end_byte.unwrap_or(0)-1].to_owned(), arg.unwrap_or("").to_string());
-                    }
-                    Some("332") => {
-                        //HANDLE topic
-                        let start_byte = command.find('#');
-                        let end_byte = command.find(':');
-                        self.chat_bounds(command[end_byte.unwrap_or(0)+1..].to_string(), server_id.clone(), command[start_byte.unwrap_or(0)..end_byte.unwrap_or(0)-1].to_owned(), arg.unwrap_or("").to_string());
-                    }
-                    Some("433") => {
-                        //HANDLE NickName in use
-                        if let Some(server) = self.server_list.get_mut(&server_id.clone()) {
+                        self.list_response.push(format!("{} {} :{}", msg.param(1), msg.param(2), msg.param(3)));
+                    }
+                    "331" | "332" => {
+                        //HANDLE no topic / topic, 33x <client> <channel> :text
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            if let Some(channel) = server.channel_mut(msg.param(1)) {
+                                channel.topic = if msg.command == "332" { Some(msg.trailing().to_string()) } else { None };
+                            }
+                        }
+                        self.chat_bounds(ChatLine::new(LineKind::Topic, &msg.command, msg.trailing()).with_tags(&msg.tags), server_id.clone(), msg.param(1).to_string());
+                    }
+                    "333" => {
+                        //RPL_TOPICWHOTIME, 333 <client> <channel> <setter> <unix time>
+                        let setter = Prefix::parse(msg.param(2)).nick;
+                        let time = msg.param(3).parse::<i64>().ok()
+                            .and_then(|t| DateTime::from_timestamp(t, 0))
+                            .map(|t| t.with_timezone(&Local));
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            if let Some(channel) = server.channel_mut(msg.param(1)) {
+                                channel.topic_setter = Some(setter.clone());
+                                channel.topic_time = time;
+                            }
+                        }
+                        let text = match time {
+                            Some(time) => format!("Topic set by {} on {}", setter, time.format("%Y-%m-%d %H:%M")),
+                            None => format!("Topic set by {}", setter),
+                        };
+                        self.chat_bounds(ChatLine::new(LineKind::Topic, &msg.command, &text).with_tags(&msg.tags), server_id.clone(), msg.param(1).to_string());
+                    }
+                    "TOPIC" => {
+                        //TOPIC <channel> :text, empty text clears it
+                        let chan = msg.param(0).to_string();
+                        let topic = msg.param(1).to_string();
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            if let Some(channel) = server.channel_mut(&chan) {
+                                channel.topic = Some(topic.clone()).filter(|t| !t.is_empty());
+                                channel.topic_setter = Some(nick.clone());
+                                channel.topic_time = Some(Local::now());
+                            }
+                        }
+                        let text = if topic.is_empty() { format!("{} cleared the topic", nick) } else { format!("{} changed the topic to: {}", nick, topic) };
+                        self.chat_bounds(ChatLine::new(LineKind::Topic, &msg.command, &text).with_tags(&msg.tags), server_id.clone(), chan);
+                    }
+                    "INVITE" => {
+                        //INVITE <nick> <channel>, someone else being invited only comes with invite-notify
+                        let invited = msg.param(0);
+                        let chan = msg.param(1).to_string();
+                        let is_me = self.server_list.get(&server_id).is_some_and(|s| s.is_me(invited));
+                        if is_me {
+                            let text = format!("{} invites you to {}, /join {} to accept", nick, chan, chan);
+                            self.chat_bounds(ChatLine::new(LineKind::System, &msg.command, &text).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                            if self.active_server == server_id && self.active_channel != "Status" {
+                                self.chat_copy(ChatLine::new(LineKind::System, &msg.command, &text).with_tags(&msg.tags), server_id.clone(), self.active_channel.clone());
+                            }
+                        } else {
+                            self.chat_bounds(ChatLine::new(LineKind::System, &msg.command, &format!("{} invited {} to {}", nick, invited, chan)).with_tags(&msg.tags), server_id.clone(), chan);
+                        }
+                    }
+                    "433" => {
+                        //HANDLE NickName in use, only pick a new one while registering
+                        if let Some(server) = self.server_list.get_mut(&server_id.clone()).filter(|s| !s.caps.registered) {
                             server.nick = server.nick.clone() + "_";
                             self.active_nick = server.nick.clone();
                             self.stream_mgr.send_line(server_id.clone(), "NICK ".to_owned() + &server.nick);
                         }
-                        //self.nick = self.nick.clone() + "_";
-                        //self.stream_mgr.send_line(server_id.clone(), "NICK ".to_owned() + &self.nick);
-                        self.chat_bounds(prefix.unwrap_or("").to_string() + " " + &command[1..], server_id.clone(), "Status".to_string(), arg.unwrap_or("").to_string());
+                        self.chat_bounds(ChatLine::new(LineKind::System, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
                     }
-                    Some("QUIT") => {
+                    "QUIT" => {
                         //HANDLE QUIT
-                        let end_bytes = line.find('!');
-                        let result = &line[1..end_bytes.unwrap_or(0)];
-                        let target = result.trim_start_matches(|c| c == '@' || c == '+');
-                        
                         if let Some(server) = self.server_list.get_mut(&server_id.clone()) {
-                            for (_channel_name, channel_data) in server.channels.iter_mut() {
-                                channel_data.user_list.retain(|user| {
-                                    let stripped_user = user.trim_start_matches(|c| c == '@' || c == '+');
-                                    stripped_user != target
-                                });
-
+                            let key = server.key(&nick);
+                            for channel_data in server.channels.values_mut() {
+                                channel_data.members.remove(&key);
                             }
                         }
-                        self.chat_bounds(prefix.unwrap_or("").to_string() + " " + &command[1..], server_id.clone(), "Status".to_string(), arg.unwrap_or("").to_string());
+                        self.chat_bounds(ChatLine::new(LineKind::Quit, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
                     }
-                    Some("PART") => {
+                    "PART" => {
                         //HANDLE PART
-                        let end_bytes = line.find('!');
-                        let result = &line[1..end_bytes.unwrap_or(1)];
-                        //let start_chan = line.find('#');
-                        let mut line_split = command.split_whitespace();
-                        let chan = line_split.next();
-
                         if let Some(server) = self.server_list.get_mut(&server_id.clone()) {
-                            if let Some(channel) = server.channels.get_mut(chan.unwrap_or("")) {
-                                channel.user_list.retain(|u| {
-                                let stripped = u.trim_start_matches(|c| c == '@' || c == '+');
-                                stripped != result
-                            });
-
+                            let is_me = server.is_me(&nick);
+                            let key = server.key(&nick);
+                            if let Some(channel) = server.channel_mut(msg.param(0)) {
+                                //Nobody is known to be in a channel we left
+                                if is_me {
+                                    channel.members.clear();
+                                    channel.parted = true;
+                                } else {
+                                    channel.members.remove(&key);
+                                }
                             }
                         }
-                        self.chat_bounds(prefix.unwrap_or("").to_string() + " " + &command[1..], server_id.clone(), "Status".to_string(), arg.unwrap_or("").to_string());
+                        self.chat_bounds(ChatLine::new(LineKind::Part, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
                     }
-                    Some("NICK") => {
+                    "NICK" => {
                         //HANDLE NICK COMMAND
-                        let end_byte = &line.find('!');
-                        let start_byte = &line.find("NICK :");
-                        let user_old = &line[1..end_byte.unwrap_or(0)];
-                        let new_user = &line[(start_byte.unwrap_or(0) + 6)..];
+                        let new_user = msg.param(0);
 
                         if let Some(server) = self.server_list.get_mut(&server_id.clone()) {
-                            if user_old == server.nick {
+                            if server.is_me(&nick) {
                                 server.nick = new_user.to_string();
+                                server.set_source(None, None);
                                 self.active_nick = new_user.to_string();
-                                self.chat_bounds("You're now known as ".to_owned() + &self.active_nick, server_id.clone(), self.active_channel.clone(), arg.unwrap_or("").to_string());
+                                self.chat_copy(ChatLine::new(LineKind::Nick, &msg.command, &format!("You're now known as {}", self.active_nick)).with_tags(&msg.tags), server_id.clone(), self.active_channel.clone());
                             }
                         }
                         if let Some(server) = self.server_list.get_mut(&server_id.clone()){
-                            for (_channel_name, channel_data) in server.channels.iter_mut() {
-                                for user in &mut channel_data.user_list {
-                                    let stripped = user.trim_start_matches(|c| c == '@' || c == '+');
-                                    if stripped == user_old {
-                                        *user = new_user.to_string();
-                                    }
+                            //Same member with its modes under the new nick
+                            let (old_key, new_key) = (server.key(&nick), server.key(new_user));
+                            for channel_data in server.channels.values_mut() {
+                                if let Some(mut member) = channel_data.members.remove(&old_key) {
+                                    member.nick = new_user.to_string();
+                                    channel_data.members.insert(new_key.clone(), member);
                                 }
                             }
                         }
-                        self.chat_bounds(prefix.unwrap_or("").to_string() + " " + &command[1..], server_id.clone(), "Status".to_string(), arg.unwrap_or("").to_string());
+                        self.chat_bounds(ChatLine::new(LineKind::Nick, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
                     }
-                    Some("JOIN") => {
-                        let end_bytes = line.find('!');
-                        let result = &line[1..end_bytes.unwrap_or(1)];
-                        let re = Regex::new(r"(#[^\s:]+)").unwrap();
-                        let chan_re = re.captures(&line).unwrap();
+                    "JOIN" => {
+                        //JOIN <channel> [account :realname] with extended-join
+                        let chan = msg.param(0).to_string();
 
+                        let mut chat_list = if self.server_list.get(&server_id).is_some_and(|s| s.is_me(&nick) && s.channel(&chan).is_none()) {
+                            self.restore_scrollback(&server_id, &chan)
+                        } else {
+                            Vec::new()
+                        };
+                        chat_list.push(ChatLine::system("Joining Channel"));
 
                         if let Some(server) = self.server_list.get_mut(&server_id.clone()) {
-                            if result == server.nick {
-                                let server_channels = &mut self.server_list.entry(server_id.clone()).or_default().channels;
-                                server_channels.entry(chan_re[1].to_owned()).or_insert(self::ChannelData { chat_list: vec![("System".to_string(),"Joining Channel".to_string())], user_list: vec![], chat_pos: 0, notification: false, });
-                                let (on, left_server, left, right_server, right) = self.split.clone();
-                                if on == true {
-                                    if self.active_channel == left {
-                                        self.split = (true, server_id.clone(), chan_re[1].to_string(), right_server, right);
-                                        self.active_channel = chan_re[1].to_owned();
-                                        self.active_server = server_id.clone();
-
-                                    } else {
-                                        self.split = (true, left_server, left, server_id.clone(), chan_re[1].to_string());
-                                        self.active_channel = chan_re[1].to_owned();
-                                        self.active_server = server_id.clone();
-                                    }
-                                } else {
-                                    self.active_channel = chan_re[1].to_owned();
+                            if server.is_me(&nick) {
+                                if let Some(source) = &msg.source {
+                                    server.set_source(source.user.as_deref(), source.host.as_deref());
+                                }
+                                let key = server.key(&chan);
+                                //A rejoin after a reconnect keeps the buffer where it is
+                                let switch = server.joining.remove(&key) || !server.channels.contains_key(&key);
+                                server.channels.entry(key).or_insert_with(|| self::ChannelData { chat_list, members: BTreeMap::new(), chat_pos: 0, opened: OpenOrder::next(), ..Default::default() });
+                                if let Some(channel) = server.channel_mut(&chan) {
+                                    channel.parted = false;
+                                }
+                                //Current channel modes for the title, answered with 324
+                                self.stream_mgr.send_line(server_id.clone(), "MODE ".to_string() + &chan);
+                                self.request_history(&server_id, &chan, false);
+                                //Shown in the focused pane
+                                if switch {
+                                    self.active_channel = chan.clone();
                                     self.active_server = server_id.clone();
                                 }
                             }
                         }
                         if let Some(server) = self.server_list.get_mut(&server_id.clone()) {
-                            if let Some(channel) = server.channels.get_mut(&chan_re[1].to_owned()) {
-                                if result != server.nick {
-                                    if !channel.user_list.contains(&result.to_string()) {
-                                        channel.user_list.push(result.to_string());
+                            let key = server.key(&nick);
+                            if let Some(channel) = server.channel_mut(&chan) {
+                                let member = channel.members.entry(key).or_insert_with(|| Member::new(&nick));
+                                if let Some(source) = &msg.source {
+                                    member.user = source.user.clone();
+                                    member.host = source.host.clone();
+                                }
+                                //extended-join adds the account (* for none) and realname
+                                if msg.params.len() >= 3 {
+                                    member.account = Some(msg.param(1).to_string()).filter(|a| a != "*");
+                                    member.realname = Some(msg.param(2).to_string());
+                                }
+                            }
+                        }
+                        self.chat_bounds(ChatLine::new(LineKind::Join, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                    }
+                    "MODE" => {
+                        //MODE <channel> <modes> [params], prefix modes go to the members, the rest to the channel
+                        let target = msg.param(0).to_string();
+                        let mut is_channel = false;
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            if server.isupport.is_channel(&target) {
+                                is_channel = true;
+                                let changes = server.isupport.mode_changes(msg.param(1), msg.params.get(2..).unwrap_or_default());
+                                let key = server.key(&target);
+                                if let Some(channel) = server.channels.get_mut(&key) {
+                                    for (adding, mode, param) in changes {
+                                        if !server.isupport.is_prefix_mode(mode) {
+                                            channel.apply_mode(&server.isupport, adding, mode, param);
+                                            continue;
+                                        }
+                                        let member = param.and_then(|p| channel.members.get_mut(&IrcKey::new(&p, server.isupport.casemapping)));
+                                        if let Some(member) = member {
+                                            if adding {
+                                                member.modes.insert(mode);
+                                            } else {
+                                                member.modes.remove(&mode);
+                                            }
+                                        }
                                     }
                                 }
                             }
                         }
-                        self.chat_bounds(prefix.unwrap_or("").to_string() + " " + &command[1..], server_id.clone(), "Status".to_string(), arg.unwrap_or("").to_string());
+                        if is_channel {
+                            let text = format!("{} sets mode {}", nick, msg.params[1..].join(" "));
+                            self.chat_bounds(ChatLine::new(LineKind::Mode, &msg.command, &text).with_tags(&msg.tags), server_id.clone(), target);
+                        } else {
+                            self.chat_bounds(ChatLine::new(LineKind::Mode, &msg.command, &msg.status_text()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
+                        }
                     }
-                    Some("353") => {
-                        let start_bytes = command.find(':');
-                        let result = &command[start_bytes.unwrap_or(0) + 1..];
-                        let list_result = result.split_whitespace();
-                        let re = Regex::new(r"(#[^\s:]+)").unwrap();
-                        let chan_re = re.captures(&command).unwrap();
+                    "324" => {
+                        //RPL_CHANNELMODEIS, 324 <client> <channel> <modes> [params], the whole set of modes
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            let changes = server.isupport.mode_changes(msg.param(2), msg.params.get(3..).unwrap_or_default());
+                            let key = server.key(msg.param(1));
+                            if let Some(channel) = server.channels.get_mut(&key) {
+                                channel.modes.clear();
+                                for (adding, mode, param) in changes {
+                                    channel.apply_mode(&server.isupport, adding, mode, param);
+                                }
+                            }
+                        }
+                    }
+                    "367" | "346" | "348" => {
+                        //Ban, invite and exception list entries, <client> <channel> <mask> ...
+                        let mode = match msg.command.as_str() {
+                            "367" => 'b',
+                            "346" => 'I',
+                            _ => 'e',
+                        };
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            if let Some(channel) = server.channel_mut(msg.param(1)) {
+                                let list = channel.lists.entry(mode).or_default();
+                                if !list.iter().any(|m| m == msg.param(2)) {
+                                    list.push(msg.param(2).to_string());
+                                }
+                            }
+                        }
+                        self.chat_bounds(ChatLine::new(LineKind::Mode, &msg.command, &format!("+{} {}", mode, msg.param(2))).with_tags(&msg.tags), server_id.clone(), msg.param(1).to_string());
+                    }
+                    "KICK" => {
+                        //KICK <channel> <nick> [:reason]
+                        let chan = msg.param(0).to_string();
+                        let kicked = msg.param(1);
+                        let mut is_me = false;
+                        if let Some(server) = self.server_list.get_mut(&server_id) {
+                            let key = server.key(kicked);
+                            is_me = server.is_me(kicked);
+                            if let Some(channel) = server.channel_mut(&chan) {
+                                if is_me {
+                                    channel.members.clear();
+                                    channel.parted = true;
+                                } else {
+                                    channel.members.remove(&key);
+                                }
+                            }
+                        }
+                        let reason = if msg.params.len() > 2 { format!(" ({})", msg.trailing()) } else { String::new() };
+                        let text = if is_me {
+                            format!("You were kicked from {} by {}{}", chan, nick, reason)
+                        } else {
+                            format!("{} was kicked by {}{}", kicked, nick, reason)
+                        };
+                        self.chat_bounds(ChatLine::new(LineKind::Kick, &msg.command, &text).with_tags(&msg.tags), server_id.clone(), chan);
+                    }
+                    "353" => {
+                        //NAMES reply, 353 <client> <symbol> <channel> :names
                         if let Some(server) = self.server_list.get_mut(&server_id.clone()) {
-                            if let Some(channel) = server.channels.get_mut(&chan_re[1].to_owned()) {
-                                for i in list_result {
-                                    if !channel.user_list.contains(&i.to_string()) {
-                                        channel.user_list.push(i.to_string());
+                            let key = server.key(msg.param(2));
+                            if let Some(channel) = server.channels.get_mut(&key) {
+                                //@+nick with multi-prefix, nick!user@host with userhost-in-names
+                                for entry in msg.trailing().split_whitespace() {
+                                    let name = server.isupport.strip_prefix(entry);
+                                    let modes: BTreeSet<char> = entry[..entry.len() - name.len()].chars().filter_map(|c| server.isupport.prefix_mode(c)).collect();
+                                    let source = Prefix::parse(name);
+                                    let member = channel.members.entry(IrcKey::new(&source.nick, server.isupport.casemapping)).or_insert_with(|| Member::new(&source.nick));
+                                    member.modes = modes;
+                                    if source.host.is_some() {
+                                        member.user = source.user;
+                                        member.host = source.host;
                                     }
                                 }
                             }
                         }
                     }
-                    Some("PRIVMSG") => {
-                        let end_bytes = line.find('!');
-                        let res_nick = &line[1..end_bytes.unwrap_or(0)];
-                        let end_mess = line.find(&(" :"));
-                        let msg_start = line.find(&("PRIVMSG "));
-                        let msg_chan = &line[msg_start.unwrap_or(0) + 8..end_mess.unwrap_or(0)];
-                        let msg = line[(end_mess.unwrap_or(0) + 2)..].to_string();
-                        if msg_chan.starts_with('#') {
-                            self.chat_bounds(msg.clone(), server_id.clone(), msg_chan.to_string(), res_nick.to_string());
+                    "PRIVMSG" => {
+                        let target = msg.param(0);
+                        let text = msg.trailing().to_string();
+                        let is_me = self.server_list.get(&server_id).is_some_and(|s| s.is_me(&nick));
+                        let ctcp = Ctcp::parse(&text).filter(|c| c.command != "ACTION");
+                        if let Some(ctcp) = ctcp {
+                            //CTCP query, answered with a NOTICE unless it is our own echo, playback or we answered too many lately
+                            if !is_me && msg.tag("batch").is_none() {
+                                if let Some(reply) = ctcp.reply().filter(|_| self.ctcp_limiter.allow()) {
+                                    self.stream_mgr.send_line(server_id.clone(), format!("NOTICE {} :{}", nick, reply));
+                                }
+                            }
+                            self.chat_bounds(ChatLine::new(LineKind::System, "CTCP", format!("CTCP {} from {} {}", ctcp.command, nick, ctcp.args).trim_end()).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
                         } else {
-                            let msg = msg_chan.to_string() + "-> " + &line[(end_mess.unwrap_or(0) + 2)..];
-                            self.chat_bounds(msg.clone(), server_id.clone(), res_nick.to_string(), res_nick.to_string());
-                            if self.active_channel != res_nick {
-                                self.chat_bounds(msg.clone(), server_id.clone(), self.active_channel.clone(), res_nick.to_string());
+                            //Actions are stored without their CTCP wrapper and drawn as * nick text
+                            let mut line = match ctcp::action(&text) {
+                                Some(action) => ChatLine::new(LineKind::Action, &nick, action),
+                                None => ChatLine::new(LineKind::Message, &nick, &text),
+                            }.with_tags(&msg.tags);
+                            //account-tag tells who is logged in without a WHO
+                            if let (Some(account), Some(server)) = (line.account(), self.server_list.get_mut(&server_id)) {
+                                server.update_member(&nick, |m| m.account = Some(account.to_string()));
+                            }
+                            let context = self.channel_target(&server_id, target).unwrap_or(nick.clone());
+                            line.highlight = !is_me && self.server_list.get(&server_id)
+                                .is_some_and(|s| self.highlights.is_highlight(&s.nick, &context, &line.text, s.isupport.casemapping));
+                            //Once per message, playback is already seen
+                            let playback = msg.tag("batch").is_some_and(|id| self.server_list.get(&server_id).is_some_and(|s| s.history.contains_key(id)));
+                            if line.highlight && !playback {
+                                self.mentions.push(Mention { server: server_id.clone(), channel: context, time: line.timestamp, sender: line.sender.clone(), text: line.text.clone() });
+                                if self.mentions.len() > MAX_MENTIONS {
+                                    self.mentions.remove(0);
+                                }
+                            }
+                            if let Some(channel) = self.channel_target(&server_id, target) {
+                                self.chat_bounds(line, server_id.clone(), channel);
+                            } else if is_me {
+                                //echo-message of our own private message
+                                self.chat_bounds(line, server_id.clone(), target.to_string());
+                            } else {
+                                if line.kind == LineKind::Message {
+                                    line.text = target.to_string() + "-> " + &line.text;
+                                }
+                                self.chat_bounds(line.clone(), server_id.clone(), nick.clone());
+                                let in_query = self.server_list.get(&server_id).is_some_and(|s| s.key(&nick).matches(&self.active_channel));
+                                if !in_query {
+                                    self.chat_copy(line, server_id.clone(), self.active_channel.clone());
+                                }
                             }
                         }
                     }
                     _ => {
-                        if !line.is_empty() && line.starts_with(':') {
-                            let command_trim: String = command[1..].chars().filter(|c| !c.is_control()).collect();
-                            self.chat_bounds(command_trim, server_id.clone(), "Status".to_string(), arg.unwrap_or("").to_string());
+                        if msg.source.is_some() {
+                            let text: String = msg.params.join(" ").chars().filter(|c| !c.is_control()).collect();
+                            self.chat_bounds(ChatLine::new(LineKind::System, &msg.command, &text).with_tags(&msg.tags), server_id.clone(), "Status".to_string());
                         }
                     }
-                } 
-            } 
+                }
+            }
             NetEvent::Error(e)   => {
-                self.chat_bounds(e.to_string(), self.active_server.clone(), self.active_channel.clone(), "Error".to_string());
+                self.chat_copy(ChatLine::error(&e.to_string()), self.active_server.clone(), self.active_channel.clone());
+            }
+            NetEvent::Connected => {
+                //New session, negotiate again and get the old nick back after registration
+                if let Some(server) = self.server_list.get_mut(&server_id) {
+                    server.caps.reset();
+                    server.source = None;
+                    server.joining.clear();
+                    server.isupport = ISupport::default();
+                    server.restore_nick = Some(server.nick.clone());
+                }
+                self.chat_bounds(ChatLine::system("Connected"), server_id.clone(), "Status".to_string());
+            }
+            NetEvent::Lag(lag) => {
+                if let Some(server) = self.server_list.get_mut(&server_id) {
+                    server.lag = Some(lag);
+                }
+            }
+            NetEvent::Queue(depth) => {
+                if let Some(server) = self.server_list.get_mut(&server_id) {
+                    server.send_queue = depth;
+                }
+            }
+            NetEvent::Disconnected(reason) => {
+                //User lists are stale until the channels are joined again
+                if let Some(server) = self.server_list.get_mut(&server_id) {
+                    server.lag = None;
+                    server.send_queue = 0;
+                    server.history.clear();
+                    for channel in server.channels.values_mut() {
+                        channel.members.clear();
+                        channel.history_pending = false;
+                        channel.history_before = None;
+                    }
+                }
+                self.chat_bounds(ChatLine::error(&reason), server_id.clone(), "Status".to_string());
             }
         }
     }
 
+    // Channel a PRIVMSG/NOTICE target points at, STATUSMSG targets like @#chan go to the channel itself
+    fn channel_target(&self, server_id: &str, target: &str) -> Option<String> {
+        let server = self.server_list.get(server_id)?;
+        let channel = server.isupport.strip_prefix(target);
+        server.isupport.is_channel(channel).then(|| channel.to_string())
+    }
+
     pub fn quit(&mut self) {
         self.stream_mgr.disconnect_all();
         self.exit = true;
     }
 
-    pub fn chat_bounds(&mut self, data: String, server_id: String, channel_id: String, nick: String) {
+    // Width of the timestamp and nick columns in front of the chat text
+    pub fn gutter_width(&self) -> usize {
+        Local::now().format(&self.timestamp_format).to_string().width() + 1 + 12
+    }
+
+    // Called after every event, the focused pane follows the active buffer,
+    // the buffer left gets its read marker and the one shown is read
+    fn buffer_switched(&mut self) {
+        self.layout.show(&self.active_server, &self.active_channel);
+        let current = (self.active_server.clone(), self.active_channel.clone());
+        if self.last_buffer == current {
+            return;
+        }
+        let (old_server, old_channel) = std::mem::replace(&mut self.last_buffer, current);
+        if let Some(channel) = self.server_list.get_mut(&old_server).and_then(|s| s.channel_mut(&old_channel)) {
+            channel.set_read_marker();
+        }
+        if let Some(channel) = self.server_list.get_mut(&self.active_server).and_then(|s| s.channel_mut(&self.active_channel)) {
+            channel.drop_stale_marker();
+            channel.mark_read();
+        }
+        self.nicklist_pos = 0;
+    }
+
+    // Every buffer as numbered in the overview and sidebar, servers and their channels in the order they were opened
+    pub fn buffer_list(&self) -> Vec<(String, String)> {
+        let mut servers: Vec<(&String, &ServerData)> = self.server_list.iter().collect();
+        servers.sort_by_key(|(_, server)| server.channels.values().map(|c| c.opened).min());
+        let mut buffers = Vec::new();
+        for (server_id, server) in servers {
+            let mut channels: Vec<_> = server.channels.iter().collect();
+            channels.sort_by_key(|(_, data)| data.opened);
+            buffers.extend(channels.into_iter().map(|(name, _)| (server_id.clone(), name.to_string())));
+        }
+        buffers
+    }
+
+    // Show a buffer in the focused pane, scrolled to the bottom
+    pub fn switch_buffer(&mut self, server_id: &str, channel_id: &str) {
+        self.active_server = server_id.to_string();
+        self.active_channel = channel_id.to_string();
+        if let Some(server) = self.server_list.get_mut(server_id) {
+            self.active_nick = server.nick.clone();
+            if let Some(channel) = server.channel_mut(channel_id) {
+                channel.chat_pos = 0;
+            }
+        }
+    }
+
+    // Alt-0..9 and /swap, false when there is no buffer with that number
+    pub fn switch_number(&mut self, number: usize) -> bool {
+        match self.buffer_list().get(number) {
+            Some((server_id, channel_id)) => {
+                self.switch_buffer(&server_id.clone(), &channel_id.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Ctrl-N/Ctrl-P and Alt-Right/Alt-Left, wrapping around at the ends
+    pub fn step_buffer(&mut self, forward: bool) {
+        let buffers = self.buffer_list();
+        if buffers.is_empty() {
+            return;
+        }
+        let current = buffers.iter().position(|(server_id, channel_id)| {
+            *server_id == self.active_server && self.server_list.get(server_id).is_some_and(|s| s.key(channel_id).matches(&self.active_channel))
+        });
+        let next = match (current, forward) {
+            (Some(i), true) => (i + 1) % buffers.len(),
+            (Some(i), false) => (i + buffers.len() - 1) % buffers.len(),
+            (None, _) => 0,
+        };
+        let (server_id, channel_id) = buffers[next].clone();
+        self.switch_buffer(&server_id, &channel_id);
+    }
+
+    // Index of the pane that shows a buffer
+    pub fn pane_of(&self, server_id: &str, channel_id: &str) -> Option<usize> {
+        let server = self.server_list.get(server_id)?;
+        let key = server.key(channel_id);
+        self.layout.panes().iter().position(|(s, c)| *s == server_id && key.matches(c))
+    }
+
+    // Area of the pane showing a buffer, the focused pane for a buffer that is not shown
+    pub fn pane_rect(&self, server_id: &str, channel_id: &str) -> Rect {
+        let area = Rect::new(0, 0, self.chat_area_width() as u16, self.h.saturating_sub(4));
+        let index = self.pane_of(server_id, channel_id).unwrap_or(self.layout.focus);
+        self.layout.rects(area).get(index).copied().unwrap_or(area)
+    }
+
+    // Chat rows of a pane, without its borders and the topic line above them
+    pub fn chat_rows(&self, server_id: &str, channel_id: &str, pane: Rect) -> usize {
+        let topic = self.server_list.get(server_id).and_then(|s| s.channel(channel_id)).is_some_and(|c| c.topic.is_some());
+        (pane.height as usize).saturating_sub(2 + topic as usize)
+    }
+
+    // Tab and /focus, the active buffer becomes the one of the focused pane
+    pub fn focus_changed(&mut self) {
+        let (server_id, channel_id) = self.layout.focused();
+        self.active_server = server_id;
+        self.active_channel = channel_id;
+        if let Some(server) = self.server_list.get(&self.active_server) {
+            self.active_nick = server.nick.clone();
+        }
+    }
+
+    // /layout <name>, panes of buffers that are not open show Status
+    pub fn restore_layout(&mut self, name: &str) -> bool {
+        let Some(root) = self.layouts.get(name).cloned() else {
+            return false;
+        };
+        self.layout.set(root);
+        let servers = &self.server_list;
+        self.layout.replace(|server, channel| servers.get(server).is_none_or(|s| s.channel(channel).is_none()), "System", "Status");
+        self.focus_changed();
+        true
+    }
+
+    // Buffers that are gone are shown as Status in every pane
+    pub fn close_panes(&mut self, matches: impl Fn(&str, &str) -> bool) {
+        self.layout.replace(matches, "System", "Status");
+        if !self.server_list.contains_key(&self.active_server) || self.pane_of(&self.active_server, &self.active_channel).is_none() {
+            self.focus_changed();
+        }
+    }
+
+    // Columns of the sidebar, none when it is off or the terminal is too narrow for it
+    pub fn sidebar_columns(&self) -> u16 {
+        if self.sidebar && self.w >= self.sidebar_width + MIN_CHAT_WIDTH { self.sidebar_width } else { 0 }
+    }
+
+    // Columns of the nick list, none when it is off, the buffer has no members or the chat would get too narrow
+    pub fn nicklist_columns(&self) -> u16 {
+        let has_members = self.server_list.get(&self.active_server)
+            .and_then(|s| s.channel(&self.active_channel))
+            .is_some_and(|c| !c.members.is_empty());
+        if self.nicklist && has_members && self.w >= self.sidebar_columns() + self.nicklist_width + MIN_CHAT_WIDTH {
+            self.nicklist_width
+        } else {
+            0
+        }
+    }
+
+    // Width left for the chat blocks
+    pub fn chat_area_width(&self) -> usize {
+        self.w.saturating_sub(self.sidebar_columns()).saturating_sub(self.nicklist_columns()) as usize
+    }
+
+    // Alt-A, the buffer with the highest activity and then the most unread messages
+    pub fn jump_active(&mut self) {
+        let mut best: Option<(Activity, usize, String, String)> = None;
+        for (server_id, server) in &self.server_list {
+            for (name, channel) in &server.channels {
+                if channel.activity == Activity::None || name.as_str() == "Status" {
+                    continue;
+                }
+                if best.as_ref().is_none_or(|(activity, unread, _, _)| (channel.activity, channel.unread) > (*activity, *unread)) {
+                    best = Some((channel.activity, channel.unread, server_id.clone(), name.to_string()));
+                }
+            }
+        }
+        if let Some((_, _, server_id, channel_id)) = best {
+            self.switch_buffer(&server_id, &channel_id);
+        }
+    }
+
+    // NETWORK from ISUPPORT, the server id until the server sent one
+    pub fn network_name(&self, server_id: &str) -> String {
+        self.server_list.get(server_id)
+            .and_then(|s| s.network.clone())
+            .unwrap_or_else(|| server_id.to_string())
+    }
+
+    // Saved lines of a buffer and a separator under them, nothing when no lines were saved
+    fn restore_scrollback(&self, server_id: &str, channel: &str) -> Vec<ChatLine> {
+        if server_id == "System" || channel == "Status" {
+            return Vec::new();
+        }
+        let Some(server) = self.server_list.get(server_id) else {
+            return Vec::new();
+        };
+        let mut lines = self.scrollback.restore(server_id, channel, server.isupport.casemapping);
+        if !lines.is_empty() {
+            lines.push(ChatLine::new(LineKind::System, "History", "──── end of saved history ────"));
+        }
+        lines
+    }
+
+    // CHATHISTORY LATEST for a new buffer, BEFORE the oldest line when scrolling past the top
+    pub fn request_history(&mut self, server_id: &str, target: &str, older: bool) {
+        let limit = self.history_lines;
+        let Some(server) = self.server_list.get_mut(server_id) else {
+            return;
+        };
+        if limit == 0 || !server.caps.has("draft/chathistory") {
+            return;
+        }
+        let limit = match server.isupport.chathistory {
+            Some(max) if max > 0 => limit.min(max),
+            _ => limit,
+        };
+        let Some(channel) = server.channel_mut(target) else {
+            return;
+        };
+        if channel.history_pending || (older && channel.history_end) {
+            return;
+        }
+        let line = if older {
+            let Some(oldest) = channel.chat_list.first() else {
+                return;
+            };
+            let reference = match oldest.msgid() {
+                Some(id) => format!("msgid={}", id),
+                None => format!("timestamp={}", oldest.timestamp.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%S%.3fZ")),
+            };
+            format!("CHATHISTORY BEFORE {} {} {}", target, reference, limit)
+        } else {
+            format!("CHATHISTORY LATEST {} * {}", target, limit)
+        };
+        channel.history_pending = true;
+        channel.history_before = older.then_some(limit);
+        self.stream_mgr.send_line(server_id.to_string(), line);
+    }
+
+    pub fn chat_bounds(&mut self, line: ChatLine, server_id: String, channel_id: String) {
+        self.add_line(line, server_id, channel_id, false);
+    }
+
+    //A line that belongs to another buffer shown here too, only the original is logged and stored
+    pub fn chat_copy(&mut self, line: ChatLine, server_id: String, channel_id: String) {
+        self.add_line(line, server_id, channel_id, true);
+    }
+
+    fn add_line(&mut self, line: ChatLine, server_id: String, channel_id: String, copy: bool) {
+
+        //Playback waits in its batch for BATCH -ref, copies for other buffers are dropped
+        if let Some(id) = line.tag("batch").map(|id| id.to_string()) {
+            if let Some(server) = self.server_list.get_mut(&server_id) {
+                let key = server.key(&channel_id);
+                if let Some(batch) = server.history.get_mut(&id) {
+                    if key.matches(&batch.target) {
+                        batch.lines.push(line);
+                    }
+                    return;
+                }
+            }
+        }
 
         //Limit length of list
         if let Some(server) = self.server_list.get_mut(&server_id) {
-            if let Some(channel) = server.channels.get_mut(&channel_id) {
+            if let Some(channel) = server.channel_mut(&channel_id) {
                 if channel.chat_list.len() > 1000 {
                 channel.chat_list = channel.chat_list.split_off(500);
                 }
             }
         }
 
-        
+        let gutter = self.gutter_width();
+        let pane_width = self.pane_rect(&server_id, &channel_id).width as usize;
+        let shown = self.pane_of(&server_id, &channel_id).is_some();
+        //Read before this line is stored, a new buffer starts with what was saved of it
+        let restored = if self.server_list.get(&server_id).is_some_and(|s| s.channel(&channel_id).is_none()) {
+            self.restore_scrollback(&server_id, &channel_id)
+        } else {
+            Vec::new()
+        };
+        let network = self.network_name(&server_id);
         if let Some(server) = self.server_list.get_mut(&server_id) {
-            match server.channels.entry(channel_id.clone()) {
+            if server_id != "System" && !copy {
+                self.logger.log(&network, &server_id, &channel_id, server.isupport.casemapping, &line);
+                //Scrollback keeps a line once, in the buffer it belongs to
+                if channel_id != "Status" {
+                    self.scrollback.store(&server_id, &channel_id, server.isupport.casemapping, &line);
+                }
+            }
+            let key = server.key(&channel_id);
+            //Level this line raises a hidden buffer to, messages in a query count as private
+            let message = matches!(line.kind, LineKind::Message | LineKind::Action | LineKind::Notice);
+            let level = if message && server_id != "System" && channel_id != "Status" && !server.isupport.is_channel(&channel_id) {
+                Activity::Private
+            } else if line.highlight {
+                Activity::Highlight
+            } else if message {
+                Activity::Messages
+            } else {
+                Activity::Events
+            };
+            match server.channels.entry(key.clone()) {
                 Entry::Occupied(mut entry) => {
-                    entry.get_mut().chat_list.push((nick, data.clone()));
-                    let (on, _left_server, left_chan, _right_server, right_chan) = self.split.clone();
+                    let data = line.text.clone();
+                    entry.get_mut().chat_list.push(line);
                     //if self.active_server == server_id && self.active_channel == channel_id {
                         if let Some(server) = self.server_list.get_mut(&server_id) {
-                            if let Some(channel) = server.channels.get_mut(&channel_id) {
+                            if let Some(channel) = server.channels.get_mut(&key) {
                                  
-                                let wrap_width;
-                                //chat window horizontal "linewrap"
-                                //let (on, _, _, _, _) = self.split;
-                                if on == true {
-                                    wrap_width = (self.w as usize / 2) - 6 - 12;
-                                    if channel_id != left_chan {
-                                        if channel_id != right_chan {
-                                            channel.notification = true;
-                                        }
-                                    }
-                                } else {
-                                    wrap_width = self.w as usize-4 - 12;
-                                    if self.active_channel != channel_id {
-                                        channel.notification = true;
-                                    }
+                                //chat window horizontal "linewrap", inside the borders of its pane
+                                let wrap_width = pane_width.saturating_sub(4 + gutter).max(1);
+                                if !shown {
+                                    channel.activity = channel.activity.max(level);
+                                    channel.unread += message as usize;
                                 }
                                 let wrap_options = Options::new(wrap_width).break_words(false);
                                 let wrapped_line = wrap(&data, wrap_options);
@@ -420,12 +1377,23 @@ impl App {
                 }
 
                 Entry::Vacant(entry) => {
-                    entry.insert(self::ChannelData {
-                        chat_list: vec![(nick, data)],
-                        user_list: vec![],
+                    let mut chat_list = restored;
+                    chat_list.push(line);
+                    let channel = entry.insert(self::ChannelData {
+                        chat_list,
+                        members: BTreeMap::new(),
                         chat_pos: 0,
-                        notification: false,
+                        opened: OpenOrder::next(),
+                        ..Default::default()
                     });
+                    if !shown {
+                        channel.activity = level;
+                        channel.unread = message as usize;
+                    }
+                    //A new query, channels ask for their history on JOIN
+                    if !server.isupport.is_channel(&channel_id) && channel_id != "Status" {
+                        self.request_history(&server_id, &channel_id, false);
+                    }
                 }
             }
         }
@@ -0,0 +1,137 @@
+// caps.rs
+use std::collections::{BTreeMap, BTreeSet};
+
+// Capabilities the client knows how to use, requested whenever the server offers them
+pub const SUPPORTED_CAPS: &[&str] = &[
+    "multi-prefix",
+    "away-notify",
+    "account-notify",
+    "account-tag",
+    "extended-join",
+    "server-time",
+    "message-tags",
+    "echo-message",
+    "batch",
+    "draft/chathistory",
+    "chghost",
+    "setname",
+    "invite-notify",
+    "sasl",
+    "twitch.tv/membership",
+];
+
+#[derive(Default, Debug)]
+pub struct CapState {
+    // Offered by the server, name -> value (sasl=PLAIN,EXTERNAL)
+    pub available: BTreeMap<String, String>,
+    // Acknowledged by the server
+    pub enabled: BTreeSet<String>,
+    // Registration is held open with CAP END until requests and SASL are done
+    pub negotiating: bool,
+    pub registered: bool,
+    pub sasl_in_progress: bool,
+    // Configured mechanism is not offered, this session registers without SASL
+    pub sasl_skipped: bool,
+    pending_req: usize,
+}
+
+impl CapState {
+    pub fn has(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        self.available.get(cap).map(|v| v.as_str())
+    }
+
+    // Connection (re)started, forget everything from the old session
+    pub fn reset(&mut self) {
+        *self = CapState::default();
+    }
+
+    // CAP * LS [*] :caps, returns true on the last line of the reply
+    pub fn ls(&mut self, caps: &str, more: bool) -> bool {
+        if !self.registered {
+            self.negotiating = true;
+        }
+        self.add_available(caps);
+        !more
+    }
+
+    // CAP * NEW :caps
+    pub fn new_caps(&mut self, caps: &str) {
+        self.add_available(caps);
+    }
+
+    // CAP * DEL :caps
+    pub fn del(&mut self, caps: &str) {
+        for cap in caps.split_whitespace() {
+            self.available.remove(cap);
+            self.enabled.remove(cap);
+        }
+    }
+
+    // CAP * ACK :caps, returns the caps that got enabled
+    pub fn ack(&mut self, caps: &str) -> Vec<String> {
+        self.pending_req = self.pending_req.saturating_sub(1);
+        let mut added = Vec::new();
+        for cap in caps.split_whitespace() {
+            if let Some(removed) = cap.strip_prefix('-') {
+                self.enabled.remove(removed);
+            } else {
+                self.enabled.insert(cap.to_string());
+                added.push(cap.to_string());
+            }
+        }
+        added
+    }
+
+    // CAP * NAK :caps, the whole request was rejected
+    pub fn nak(&mut self) {
+        self.pending_req = self.pending_req.saturating_sub(1);
+    }
+
+    // CAP REQ lines for every supported cap the server offers that is not enabled yet
+    pub fn request_lines(&mut self, want_sasl: bool) -> Vec<String> {
+        let wanted: Vec<&str> = SUPPORTED_CAPS.iter()
+            .copied()
+            .filter(|cap| self.available.contains_key(*cap) && !self.enabled.contains(*cap))
+            .filter(|cap| want_sasl || *cap != "sasl")
+            .collect();
+
+        //Each REQ is accepted or rejected as a whole, keep the lines short
+        let mut lines = Vec::new();
+        let mut current = String::new();
+        for cap in wanted {
+            if !current.is_empty() && current.len() + cap.len() > 400 {
+                lines.push(format!("CAP REQ :{}", current));
+                current.clear();
+            }
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(cap);
+        }
+        if !current.is_empty() {
+            lines.push(format!("CAP REQ :{}", current));
+        }
+        self.pending_req += lines.len();
+        lines
+    }
+
+    // True once, when CAP END should be sent to finish registration
+    pub fn end_ready(&mut self) -> bool {
+        if self.negotiating && self.pending_req == 0 && !self.sasl_in_progress {
+            self.negotiating = false;
+            return true;
+        }
+        false
+    }
+
+    fn add_available(&mut self, caps: &str) {
+        for cap in caps.split_whitespace() {
+            let (name, value) = cap.split_once('=').unwrap_or((cap, ""));
+            self.available.insert(name.to_string(), value.to_string());
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// casemap.rs
+use std::cmp::Ordering;
+use std::fmt;
+
+// How the server folds nicks and channel names to compare them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaseMapping {
+    Ascii,
+    #[default]
+    Rfc1459,
+    Rfc1459Strict,
+}
+
+impl CaseMapping {
+    pub fn parse(value: &str) -> CaseMapping {
+        match value {
+            "ascii" => CaseMapping::Ascii,
+            "strict-rfc1459" => CaseMapping::Rfc1459Strict,
+            _ => CaseMapping::Rfc1459,
+        }
+    }
+
+    // rfc1459 also treats []\~ as the lower case of {}|^, strict leaves out ~ and ^
+    pub fn fold(&self, name: &str) -> String {
+        name.chars().map(|c| match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Rfc1459 | CaseMapping::Rfc1459Strict, '[') => '{',
+            (CaseMapping::Rfc1459 | CaseMapping::Rfc1459Strict, ']') => '}',
+            (CaseMapping::Rfc1459 | CaseMapping::Rfc1459Strict, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            _ => c,
+        }).collect()
+    }
+
+    pub fn equal(&self, a: &str, b: &str) -> bool {
+        a.len() == b.len() && self.fold(a) == self.fold(b)
+    }
+}
+
+// Channel or nick as a map key, compared folded but displayed as the server first spelled it
+#[derive(Clone, Debug)]
+pub struct IrcKey {
+    name: String,
+    folded: String,
+    mapping: CaseMapping,
+}
+
+impl IrcKey {
+    pub fn new(name: &str, mapping: CaseMapping) -> IrcKey {
+        IrcKey { name: name.to_string(), folded: mapping.fold(name), mapping }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    // Same name as this key in its casemapping
+    pub fn matches(&self, name: &str) -> bool {
+        self.folded == self.mapping.fold(name)
+    }
+}
+
+impl PartialEq for IrcKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.folded == other.folded
+    }
+}
+
+impl Eq for IrcKey {}
+
+impl PartialOrd for IrcKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IrcKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.folded.cmp(&other.folded)
+    }
+}
+
+impl fmt::Display for IrcKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
@@ -0,0 +1,97 @@
+// chatline.rs
+use chrono::{DateTime, Local};
+use chrono::format::{Item, StrftimeItems};
+
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%H:%M";
+pub const DEFAULT_HISTORY_LINES: usize = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineKind {
+    Message,
+    Action,
+    Notice,
+    Join,
+    Part,
+    Quit,
+    Kick,
+    Nick,
+    Mode,
+    Topic,
+    Error,
+    System,
+    // Read marker, where the buffer was left
+    Marker,
+}
+
+// One line in a buffer, sender is the nick for messages and the command name for events
+#[derive(Clone, Debug)]
+pub struct ChatLine {
+    pub timestamp: DateTime<Local>,
+    pub kind: LineKind,
+    pub sender: String,
+    pub text: String,
+    // IRCv3 message tags of the line it came from
+    pub tags: Vec<(String, Option<String>)>,
+    pub highlight: bool,
+}
+
+impl ChatLine {
+    pub fn new(kind: LineKind, sender: &str, text: &str) -> ChatLine {
+        ChatLine {
+            timestamp: Local::now(),
+            kind,
+            sender: sender.to_string(),
+            text: text.to_string(),
+            tags: Vec::new(),
+            highlight: false,
+        }
+    }
+
+    // Keep the tags of the server line, @time replaces the time we received it
+    pub fn with_tags(mut self, tags: &[(String, Option<String>)]) -> ChatLine {
+        self.tags = tags.to_vec();
+        if let Some(time) = self.tag("time").and_then(|t| DateTime::parse_from_rfc3339(t).ok()) {
+            self.timestamp = time.with_timezone(&Local);
+        }
+        self
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(key, _)| key == name).and_then(|(_, value)| value.as_deref())
+    }
+
+    pub fn msgid(&self) -> Option<&str> {
+        self.tag("msgid")
+    }
+
+    pub fn account(&self) -> Option<&str> {
+        self.tag("account")
+    }
+
+    // msgid of the message this one answers
+    pub fn reply_to(&self) -> Option<&str> {
+        self.tag("+draft/reply").or_else(|| self.tag("+reply"))
+    }
+
+    pub fn system(text: &str) -> ChatLine {
+        ChatLine::new(LineKind::System, "System", text)
+    }
+
+    pub fn error(text: &str) -> ChatLine {
+        ChatLine::new(LineKind::Error, "Error", text)
+    }
+
+    pub fn read_marker() -> ChatLine {
+        ChatLine::new(LineKind::Marker, "", "──────── new ────────")
+    }
+
+    // Channel events like JOIN/PART are drawn dimmed
+    pub fn is_event(&self) -> bool {
+        matches!(self.kind, LineKind::Join | LineKind::Part | LineKind::Quit | LineKind::Kick | LineKind::Nick | LineKind::Mode | LineKind::Topic)
+    }
+}
+
+// An unknown % in a strftime format would panic when formatting, fall back to the default
+pub fn valid_timestamp_format(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| matches!(item, Item::Error))
+}
@@ -1,11 +1,18 @@
 use std::{collections::HashMap, fs, path::Path};
 use serde::Deserialize;
 use crate::app::App;
-use crate::stream::{ServerId, NetEvent};
+use crate::stream::{ServerId, NetEvent, ConnectInfo, FLOOD_BURST, FLOOD_RATE, TWITCH_FLOOD_BURST, TWITCH_FLOOD_RATE};
 use std::collections::BTreeMap;
+use std::time::Duration;
 use std::collections::btree_map::Entry;
 use crate::app::ServerData;
-use crate::app::ChannelData;
+use crate::app::{ChannelData, DEFAULT_NICKLIST_WIDTH, DEFAULT_SIDEBAR_WIDTH};
+use crate::casemap::{CaseMapping, IrcKey};
+use crate::sasl::SaslConfig;
+use crate::layout::{self, Node};
+use crate::logger::{LogFormat, ServerLog};
+use crate::scrollback::DEFAULT_SCROLLBACK_LINES;
+use crate::chatline::{ChatLine, DEFAULT_HISTORY_LINES, DEFAULT_TIMESTAMP_FORMAT, valid_timestamp_format};
 
 // Root struct, all sections optional
 #[derive(Debug, Deserialize)]
@@ -14,11 +21,38 @@ struct Config {
     theme: Option<Theme>,
     twitch: Option<Twitch>,
     autojoin: Option<AutoJoin>,
+    logging: Option<Logging>,
+    highlight: Option<Highlight>,
+    layouts: Option<BTreeMap<String, Node>>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ClientConfig {
     nick: String,
+    // CTCP replies allowed per ctcp_period seconds
+    #[serde(default = "ctcp_limit")]
+    ctcp_limit: usize,
+    #[serde(default = "ctcp_period")]
+    ctcp_period: u64,
+    // strftime format, %H:%M:%S for seconds
+    #[serde(default = "timestamp_format")]
+    timestamp_format: String,
+    // Lines of draft/chathistory asked for on join, 0 turns it off
+    #[serde(default = "history_lines")]
+    history_lines: usize,
+    // Lines saved per buffer and restored when it is opened again, 0 turns it off
+    #[serde(default = "scrollback_lines")]
+    scrollback_lines: usize,
+    // Buffer tree left of the chat, /sidebar toggles and resizes it
+    #[serde(default)]
+    sidebar: bool,
+    #[serde(default = "sidebar_width")]
+    sidebar_width: u16,
+    // Members of the active channel right of the chat, /nicklist toggles and resizes it
+    #[serde(default)]
+    nicklist: bool,
+    #[serde(default = "nicklist_width")]
+    nicklist_width: u16,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,10 +64,36 @@ struct Theme {
     text: Vec<u8>,
 }
 
+#[derive(Debug, Deserialize)]
+struct Logging {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default)]
+    format: LogFormat,
+    // Per server switches, with per channel switches inside
+    #[serde(default)]
+    servers: HashMap<String, ServerLog>,
+}
+
+// Words and regexes that highlight a line besides our nick, [highlight.channels."#rust"] adds more for one channel
+#[derive(Debug, Default, Deserialize)]
+struct Highlight {
+    #[serde(default)]
+    words: Vec<String>,
+    #[serde(default)]
+    patterns: Vec<String>,
+    #[serde(default)]
+    channels: HashMap<String, Highlight>,
+}
+
 #[derive(Debug, Deserialize)]
 struct Twitch {
     nick: String,
     oauth: String,
+    #[serde(default = "twitch_flood_burst")]
+    flood_burst: u32,
+    #[serde(default = "twitch_flood_rate")]
+    flood_rate: f64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -47,8 +107,43 @@ struct Server {
     ip: String,
     nick: String,
     channels: Vec<String>,
+    #[serde(default)]
+    tls: bool,
+    #[serde(default)]
+    tls_ca: String,
+    #[serde(default)]
+    sasl_mechanism: String,
+    #[serde(default)]
+    sasl_account: String,
+    #[serde(default)]
+    sasl_password: String,
+    #[serde(default)]
+    client_cert: String,
+    #[serde(default = "default_true")]
+    auto_reconnect: bool,
+    #[serde(default = "flood_burst")]
+    flood_burst: u32,
+    #[serde(default = "flood_rate")]
+    flood_rate: f64,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+// Outgoing flood limits, lines sent at once and lines per second after that
+fn flood_burst() -> u32 { FLOOD_BURST }
+fn flood_rate() -> f64 { FLOOD_RATE }
+fn twitch_flood_burst() -> u32 { TWITCH_FLOOD_BURST }
+fn twitch_flood_rate() -> f64 { TWITCH_FLOOD_RATE }
+fn ctcp_limit() -> usize { 3 }
+fn ctcp_period() -> u64 { 10 }
+fn timestamp_format() -> String { DEFAULT_TIMESTAMP_FORMAT.to_string() }
+fn history_lines() -> usize { DEFAULT_HISTORY_LINES }
+fn scrollback_lines() -> usize { DEFAULT_SCROLLBACK_LINES }
+fn sidebar_width() -> u16 { DEFAULT_SIDEBAR_WIDTH }
+fn nicklist_width() -> u16 { DEFAULT_NICKLIST_WIDTH }
+
 fn read_file() -> Result<Config, Box<dyn std::error::Error>> {
     let mut path = dirs_next::home_dir().expect("could not find home dir");
     path.push(".config/rustychat/config.toml");
@@ -70,16 +165,47 @@ pub fn read_config(app: &mut App) {
         Ok(config) => {
             if let Some(conf) = &config.config {
                 app.active_nick = conf.nick.clone();
+                app.ctcp_limiter.limit = conf.ctcp_limit;
+                app.ctcp_limiter.period = Duration::from_secs(conf.ctcp_period);
+                app.history_lines = conf.history_lines;
+                app.scrollback.lines = conf.scrollback_lines;
+                app.sidebar = conf.sidebar;
+                app.sidebar_width = conf.sidebar_width.max(8);
+                app.nicklist = conf.nicklist;
+                app.nicklist_width = conf.nicklist_width.max(8);
+                if valid_timestamp_format(&conf.timestamp_format) {
+                    app.timestamp_format = conf.timestamp_format.clone();
+                }
                 if let Some(server) = app.server_list.get_mut(&"System".to_string()) {
                     server.nick = conf.nick.clone();
                 }
             } else {
                 //No Config Section Found
             }
+            if let Some(logging) = config.logging {
+                app.logger.enabled = logging.enabled;
+                app.logger.format = logging.format;
+                app.logger.servers = logging.servers;
+                //Started even when off globally, a server or channel can still turn it on
+                app.logger.start();
+            }
+            if let Some(layouts) = config.layouts {
+                app.layouts = layouts;
+            }
+            if let Some(highlight) = config.highlight {
+                let mut invalid = app.highlights.add(None, &highlight.words, &highlight.patterns);
+                for (channel, rules) in &highlight.channels {
+                    invalid.extend(app.highlights.add(Some(channel), &rules.words, &rules.patterns));
+                }
+                for pattern in invalid {
+                    app.chat_bounds(ChatLine::error(&format!("Invalid highlight pattern: {}", pattern)), "System".to_string(), "Status".to_string());
+                }
+            }
         }
         Err(_e) => {}
     }
-    
+    //Saved with /layout save, a saved layout wins over one of the same name in the config
+    app.layouts.extend(layout::read_saved());
 }
 
 pub fn read_twitch() -> (String, String) {
@@ -99,6 +225,16 @@ pub fn read_twitch() -> (String, String) {
     }
 }
 
+pub fn read_twitch_flood() -> (u32, f64) {
+    match read_file() {
+        Ok(config) => match &config.twitch {
+            Some(tw) => (tw.flood_burst, tw.flood_rate),
+            None => (TWITCH_FLOOD_BURST, TWITCH_FLOOD_RATE),
+        },
+        Err(_e) => (TWITCH_FLOOD_BURST, TWITCH_FLOOD_RATE),
+    }
+}
+
 pub async fn read_autojoin (app: &mut App, net_tx: &tokio::sync::mpsc::UnboundedSender<(ServerId, NetEvent)>) {
     let config_result = read_file();
     
@@ -107,29 +243,60 @@ pub async fn read_autojoin (app: &mut App, net_tx: &tokio::sync::mpsc::Unbounded
             if let Some(auto) = &autojoin.autojoin {
                 for (_, server) in &auto.servers {
                     //app.chat_bounds(server.ip.to_string(), "System".to_string(), "Status".to_string(), "Error".to_string());
-                    let port = ":6667".to_string();
+                    let port = if server.tls { ":6697".to_string() } else { ":6667".to_string() };
                     let server_id: String = server.ip.to_string();
                     let addr: String = server_id.clone() + &port;
 
-                    if app.stream_mgr.connect(server_id.clone(), addr.to_string(), net_tx.clone(), server.nick.clone(), app.real.clone(), "".to_string()).await {
+                    //Mechanism defaults to PLAIN if only account is set, and EXTERNAL if only a client cert is set
+                    let mechanism = if !server.sasl_mechanism.is_empty() {
+                        server.sasl_mechanism.as_str()
+                    } else if !server.sasl_account.is_empty() {
+                        "PLAIN"
+                    } else if !server.client_cert.is_empty() {
+                        "EXTERNAL"
+                    } else {
+                        ""
+                    };
+                    let sasl = SaslConfig::from_config(mechanism, &server.sasl_account, &server.sasl_password);
+
+                    let info = ConnectInfo {
+                        addr: addr.clone(),
+                        nick: server.nick.clone(),
+                        real: app.real.clone(),
+                        tls: server.tls,
+                        tls_ca: if server.tls_ca.is_empty() { None } else { Some(server.tls_ca.clone()) },
+                        client_cert: if server.client_cert.is_empty() { None } else { Some(server.client_cert.clone()) },
+                        auto_reconnect: server.auto_reconnect,
+                        flood_burst: server.flood_burst,
+                        flood_rate: server.flood_rate,
+                        ..Default::default()
+                    };
+                    if app.stream_mgr.connect(server_id.clone(), info, net_tx.clone()).await {
                         match app.server_list.entry(server_id.clone()) {
                             Entry::Occupied(o) => o.into_mut(),
                             Entry::Vacant(v) => {
                             // Create a new HashMap with the "Status" channel already inserted
                                 let mut channels = BTreeMap::new();
-                                channels.insert("Status".to_string(), ChannelData::default());
+                                channels.insert(IrcKey::new("Status", CaseMapping::default()), ChannelData::new());
                                 v.insert(ServerData {
                                     channels,
                                     nick: server.nick.clone(),
+                                    sasl,
+                                    ..Default::default()
                                 })
                             }
                         };
                         app.active_server = server_id.clone();
                         app.active_channel = "Status".to_string();
                         app.active_nick = server.nick.clone();
+                        //The certificate is only presented in the TLS handshake
+                        let cert_without_tls = !server.client_cert.is_empty() && !server.tls;
                         if let Some(server) =  app.server_list.get_mut(&server_id) {
-                            if let Some(channel) = server.channels.get_mut(&"Status".to_string()) {
-                                channel.chat_list.push(("System".to_string(),String::from(format!("<connecting to {}>", addr))));
+                            if let Some(channel) = server.channel_mut("Status") {
+                                channel.chat_list.push(ChatLine::system(&format!("<connecting to {}>", addr)));
+                                if cert_without_tls {
+                                    channel.chat_list.push(ChatLine::error("client_cert is set without tls = true, the certificate is not sent"));
+                                }
                             }
                         }
                     }
@@ -150,8 +317,11 @@ pub fn autojoin_channel (app: &mut App, server_id: ServerId) {
             if let Some(auto) = &autojoin.autojoin {
                 for (_, server) in &auto.servers {
                     if server.ip == server_id {
-                        for channel in server.channels.clone() {
-                            app.stream_mgr.send_line(server_id.clone(), "JOIN ".to_string() + &channel);
+                        //Channels already open were rejoined by the 001 handler
+                        let joined = |c: &str| app.server_list.get(&server_id).is_some_and(|s| s.channel(c).is_some());
+                        let wanted: Vec<&String> = server.channels.iter().filter(|c| !c.is_empty() && !joined(c)).collect();
+                        for channel in wanted {
+                            app.stream_mgr.send_line(server_id.clone(), "JOIN ".to_string() + channel);
                         }
                     }
                 }
@@ -0,0 +1,95 @@
+// ctcp.rs
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use chrono::Local;
+
+pub const CLIENTINFO: &str = "ACTION CLIENTINFO PING SOURCE TIME VERSION";
+const SOURCE: &str = "https://github.com/MagiMartin/RustyChat";
+
+// \x01COMMAND args\x01 inside a PRIVMSG or NOTICE
+#[derive(Debug, PartialEq, Eq)]
+pub struct Ctcp {
+    pub command: String,
+    pub args: String,
+}
+
+impl Ctcp {
+    // The closing \x01 is optional, some clients leave it out
+    pub fn parse(text: &str) -> Option<Ctcp> {
+        let inner = text.strip_prefix('\u{1}')?;
+        let inner = inner.strip_suffix('\u{1}').unwrap_or(inner);
+        let (command, args) = inner.split_once(' ').unwrap_or((inner, ""));
+        if command.is_empty() {
+            return None;
+        }
+        Some(Ctcp { command: command.to_uppercase(), args: args.to_string() })
+    }
+
+    // Answer for a query, sent back as a NOTICE, None for unknown queries and ACTION
+    pub fn reply(&self) -> Option<String> {
+        let args = match self.command.as_str() {
+            "VERSION" => format!("RustyChat {}", env!("CARGO_PKG_VERSION")),
+            "PING" => self.args.clone(),
+            "TIME" => Local::now().format("%a %b %d %H:%M:%S %Y").to_string(),
+            "CLIENTINFO" => CLIENTINFO.to_string(),
+            "SOURCE" => SOURCE.to_string(),
+            _ => return None,
+        };
+        Some(encode(&self.command, &args))
+    }
+}
+
+pub fn encode(command: &str, args: &str) -> String {
+    if args.is_empty() {
+        format!("\u{1}{}\u{1}", command)
+    } else {
+        format!("\u{1}{} {}\u{1}", command, args)
+    }
+}
+
+// Text of a /me line, or None if it is not an ACTION
+pub fn action(text: &str) -> Option<&str> {
+    let inner = text.strip_prefix("\u{1}ACTION")?;
+    let inner = inner.strip_suffix('\u{1}').unwrap_or(inner);
+    Some(inner.strip_prefix(' ').unwrap_or(inner))
+}
+
+// Milliseconds since the epoch, sent with /ctcp PING so the reply gives the round trip
+pub fn ping_token() -> String {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis()).to_string()
+}
+
+// Round trip of a PING reply carrying our own ping_token
+pub fn ping_rtt(token: &str) -> Option<Duration> {
+    let sent: u128 = token.trim().parse().ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_millis();
+    now.checked_sub(sent).map(|ms| Duration::from_millis(ms as u64))
+}
+
+// At most limit replies per period, so a flood of queries can not get us killed for flooding
+#[derive(Debug)]
+pub struct ReplyLimiter {
+    pub limit: usize,
+    pub period: Duration,
+    sent: VecDeque<Instant>,
+}
+
+impl Default for ReplyLimiter {
+    fn default() -> Self {
+        ReplyLimiter { limit: 3, period: Duration::from_secs(10), sent: VecDeque::new() }
+    }
+}
+
+impl ReplyLimiter {
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        while self.sent.front().is_some_and(|t| now.duration_since(*t) > self.period) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.limit {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
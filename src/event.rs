@@ -5,16 +5,21 @@ use crate::config;
 use crate::app::ServerData;
 //use std::fs;
 use crate::app::ChannelData;
+use crate::casemap::{CaseMapping, IrcKey};
 //use crate::tui;
 use ratatui::text::Span;
-use crate::stream::{ServerId, NetEvent};
+use crate::stream::{ServerId, NetEvent, ConnectInfo, FLOOD_BURST, FLOOD_RATE};
 use crossterm::event::{self, Event, KeyCode, KeyModifiers};
 use tokio::sync::mpsc::UnboundedSender;
 use regex::Regex;
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
-use textwrap::{wrap, Options};
 use crate::cursor;
+use crate::textstyle;
+use crate::ctcp;
+use crate::search;
+use crate::layout::{self, Node, SplitDir};
+use crate::chatline::{ChatLine, LineKind};
 
 pub enum AppEvent {
     InputEdit(char),
@@ -28,11 +33,16 @@ pub enum AppEvent {
     InputDelete,
     InputEscape,
     ListHistory(KeyCode),
-    SplitSwap,
+    PaneCycle,
     StyleSwitch(char),
+    Search,
+    JumpActive,
+    BufferNumber(usize),
+    BufferStep(bool),
+    NickListScroll(KeyCode),
 }
 
-static CTRL_KEYS: &[char] = &['s', 'b', 'k', 'u', 'n'];
+static CTRL_KEYS: &[char] = &['s', 'b', 'k', 'u', 'o'];
 
 pub async fn input_event_loop(sender: UnboundedSender<AppEvent>) {
     loop {
@@ -44,12 +54,25 @@ pub async fn input_event_loop(sender: UnboundedSender<AppEvent>) {
                 Event::Key(key) => {
                     match key.code {
                         KeyCode::Char(c) => {
-                            if key.modifiers.contains(KeyModifiers::CONTROL) && CTRL_KEYS.contains(&c) {
+                            if key.modifiers.contains(KeyModifiers::CONTROL) && c == 'f' {
+                                if sender.send(AppEvent::Search).is_err() { break; }
+                            } else if key.modifiers.contains(KeyModifiers::ALT) && c == 'a' {
+                                if sender.send(AppEvent::JumpActive).is_err() { break; }
+                            } else if key.modifiers.contains(KeyModifiers::ALT) && c.is_ascii_digit() {
+                                if sender.send(AppEvent::BufferNumber(c as usize - '0' as usize)).is_err() { break; }
+                            } else if key.modifiers.contains(KeyModifiers::CONTROL) && (c == 'n' || c == 'p') {
+                                if sender.send(AppEvent::BufferStep(c == 'n')).is_err() { break; }
+                            } else if key.modifiers.contains(KeyModifiers::CONTROL) && CTRL_KEYS.contains(&c) {
                                 if sender.send(AppEvent::StyleSwitch(c)).is_err() { break; }
                             } else {
                                 if sender.send(AppEvent::InputEdit(c)).is_err() { break; }
                             }
                         }
+                        KeyCode::Left | KeyCode::Right if key.modifiers.contains(KeyModifiers::ALT) => {
+                            //Alt-Right is the next buffer, Alt-Left the one before
+                            let event = AppEvent::BufferStep(key.code == KeyCode::Right);
+                            if sender.send(event).is_err() { break; }
+                        }
                         KeyCode::Left => {
                            if sender.send(AppEvent::KeyLeft).is_err() { break; }
                         }
@@ -68,11 +91,10 @@ pub async fn input_event_loop(sender: UnboundedSender<AppEvent>) {
                         KeyCode::F(_) => {
                             if sender.send(AppEvent::Popup(key.code)).is_err() { break; }
                         }
-                        KeyCode::Up => {
-                            if sender.send(AppEvent::PromptHistory(key.code)).is_err() { break; }
-                        }
-                        KeyCode::Down => {
-                            if sender.send(AppEvent::PromptHistory(key.code)).is_err() { break; }
+                        KeyCode::Up | KeyCode::Down => {
+                            //Alt scrolls the nick list instead of the prompt history
+                            let event = if key.modifiers.contains(KeyModifiers::ALT) { AppEvent::NickListScroll(key.code) } else { AppEvent::PromptHistory(key.code) };
+                            if sender.send(event).is_err() { break; }
                         }
                         KeyCode::Esc => {
                             if sender.send(AppEvent::InputEscape).is_err() { break; }
@@ -83,9 +105,7 @@ pub async fn input_event_loop(sender: UnboundedSender<AppEvent>) {
                         KeyCode::PageDown => {
                             if sender.send(AppEvent::ListHistory(key.code)).is_err() { break; }
                         }
-                        KeyCode::Tab => {
-                            if sender.send(AppEvent::SplitSwap).is_err() { break; }
-                        }
+                        KeyCode::Tab if sender.send(AppEvent::PaneCycle).is_err() => break,
                         _ => {}
                     }
                 }
@@ -106,6 +126,29 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
             //app.prompt.push(c); 
             //tui::enter_char(c, app);
             cursor::enter_char(app, c);
+            if app.search.typing {
+                search::pattern_changed(app);
+            }
+        }
+        AppEvent::Search => {
+            search::start_typing(app);
+        }
+        AppEvent::JumpActive => {
+            app.jump_active();
+        }
+        AppEvent::BufferNumber(number) => {
+            app.switch_number(number);
+        }
+        AppEvent::BufferStep(forward) => {
+            app.step_buffer(forward);
+        }
+        AppEvent::NickListScroll(key) => {
+            //Clamped to the member count when drawn
+            if key == KeyCode::Up {
+                app.nicklist_pos = app.nicklist_pos.saturating_sub(1);
+            } else {
+                app.nicklist_pos = app.nicklist_pos.saturating_add(1);
+            }
         }
         AppEvent::KeyLeft => {
             //tui::move_cursor_left(app);
@@ -203,7 +246,7 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                     let map = cursor::build_prompt_cursor_map(&app.prompt);
                     cursor::move_cursor_right(app, &map);
                 }
-                'n' => {
+                'o' => {
                     app.prompt.push('\u{F}');
                     app.input_mode.clear();
                     app.input_mode.push(Span::from("N"));
@@ -215,25 +258,18 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                 _ => {}
             }
         }
-        AppEvent::SplitSwap => {
-            let (toggle, server_left, left, server_right, right) = &app.split;
-            if *toggle == true {
-                if app.active_server == *server_left && app.active_channel == *left {
-                    //app.chat_pos = 0;
-                    app.active_server = server_right.to_string() ;
-                    app.active_channel = right.to_string();
-                    if let Some(server) = app.server_list.get(&server_right.clone()) {
-                        app.active_nick = server.nick.clone();
-                    }
-                } else {
-                   // app.chat_pos = 0;
-                    app.active_server = server_left.to_string();
-                    app.active_channel = left.to_string();
-                    if let Some(server) = app.server_list.get(&server_left.clone()) {
-                        app.active_nick = server.nick.clone();
-                    }
-                }
-            }
+        AppEvent::PaneCycle => {
+            app.layout.cycle();
+            app.focus_changed();
+        }
+        AppEvent::PromptHistory(key) if app.search.typing => {
+            //Up goes to older matches, Down to newer ones
+            search::step(app, key == KeyCode::Up);
+        }
+        AppEvent::PromptHistory(key) if app.popup == Popup::Search => {
+            let last = app.search.hits.len().saturating_sub(1);
+            let selected = app.search_state.selected().unwrap_or(0);
+            app.search_state.select(Some(if key == KeyCode::Up { selected.saturating_sub(1) } else { (selected + 1).min(last) }));
         }
         AppEvent::PromptHistory(key) => {
             match key {
@@ -282,27 +318,16 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                     } else if app.popup == Popup::User {
                         app.menu_pos = app.menu_pos.saturating_sub(1);
                     } else {
+                        let pane = app.pane_rect(&app.active_server, &app.active_channel);
+                        let chat_rows = app.chat_rows(&app.active_server, &app.active_channel, pane);
+                        //Rows as the pane draws them, wrapped inside its borders
+                        let total_rows = textstyle::total_rows(app, &app.active_server, &app.active_channel);
                         if let Some(server) = app.server_list.get_mut(&app.active_server) {
-                            if let Some(channel) = server.channels.get_mut(&app.active_channel) {
-                                
-                                let raw_lines: Vec<&str> = channel.chat_list.iter().map(|(_, line)| line.as_str()).collect(); 
-                                let wrap_width;
-                                //chat window horizontal "linewrap"
-                                let (on, _, _, _, _) = app.split;
-                                if on == true {
-                                    wrap_width = (app.w as usize / 2) - 6 - 12;
-                                } else {
-                                    wrap_width = app.w as usize-4 - 12;
-                                }
-
-                                let wrap_options = Options::new(wrap_width).break_words(false);
-                                let wrapped_lines: Vec<String> = raw_lines.iter()
-                                  .flat_map(|line| wrap(line, &wrap_options))
-                                    .map(|cow| cow.into_owned())
-                                    .collect();
-                                
-                                if channel.chat_pos == wrapped_lines.len().saturating_sub(app.h as usize - 6) {
-                                    //Strop Scroll
+                            if let Some(channel) = server.channel_mut(&app.active_channel) {
+                                if channel.chat_pos == total_rows.saturating_sub(chat_rows) {
+                                    //Strop Scroll, past the top ask the server for older lines
+                                    let (server_id, channel_id) = (app.active_server.clone(), app.active_channel.clone());
+                                    app.request_history(&server_id, &channel_id, true);
                                 } else {
                                     channel.chat_pos = channel.chat_pos.saturating_add(1);
                                 }
@@ -321,8 +346,8 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                     } else if app.popup == Popup::User {
                         //let mut user_length: usize = 0;
                         if let Some(server) = app.server_list.get(&app.active_server) {
-                            if let Some(channel) = server.channels.get(&app.active_channel) {
-                                let user_length = channel.user_list.len();
+                            if let Some(channel) = server.channel(&app.active_channel) {
+                                let user_length = channel.members.len();
 
                                 if app.menu_pos >= user_length.saturating_sub(((app.h as usize * 70) / 100) - 1) {
                                     //Stop Scrolloing
@@ -333,7 +358,7 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                         };
                     } else {
                         if let Some(server) = app.server_list.get_mut(&app.active_server) {
-                            if let Some(channel) = server.channels.get_mut(&app.active_channel) {
+                            if let Some(channel) = server.channel_mut(&app.active_channel) {
                                 channel.chat_pos = channel.chat_pos.saturating_sub(1);
                             }
                         }
@@ -345,6 +370,9 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
         AppEvent::InputBackspace => { 
             //tui::delete_char(app);
             cursor::delete_char(app);
+            if app.search.typing {
+                search::pattern_changed(app);
+            }
         }
         AppEvent::InputDelete => {
             if app.character_index == app.prompt.len() {
@@ -353,6 +381,7 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                 }
         }
         AppEvent::InputEscape => {
+            search::stop(app);
             app.popup = Popup::None;
             app.list_response.clear();
             /*if app.list_popup == true {
@@ -394,10 +423,25 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                         app.active_tab = 2;
                     }
                 },
+                KeyCode::F(4) => {
+                    if app.popup == Popup::Mentions {
+                        app.popup = Popup::None;
+                        app.active_tab = usize::MAX;
+                    } else {
+                        app.popup = Popup::Mentions;
+                        app.active_tab = 3;
+                    }
+                },
                 _ => {},
             }
 
         }
+        AppEvent::InputSend if app.search.typing => {
+            search::step(app, true);
+        }
+        AppEvent::InputSend if app.popup == Popup::Search && app.prompt.is_empty() => {
+            search::open_selected(app);
+        }
         AppEvent::InputSend => {
             let line = app.prompt.clone();
             app.prompt_list.push(line.clone());
@@ -407,27 +451,44 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                     s if s.to_uppercase().starts_with("/TWITCH_CONNECT") => {
                         //Handle Tiwtch Connection
                         let tw_serv = "irc.chat.twitch.tv";
-                        let tw_port = ":6667";
+                        let tw_port = ":6697";
                         //match fs::read_to_string("oauth") {
                         //    Ok(n) => {
                         let (tw_nick, oauth) = config::read_twitch();
+                        let (flood_burst, flood_rate) = config::read_twitch_flood();
                         if oauth == "Error" {
-                            app.chat_bounds(tw_nick.to_owned(), app.active_server.clone(), app.active_channel.clone(), "ERROR".to_string());
+                            app.chat_bounds(ChatLine::error(&tw_nick), app.active_server.clone(), app.active_channel.clone());
                         } else {
                                 //let mut oauth_iter = n.lines();
                                 //let tw_nick = oauth_iter.next().unwrap_or(&app.active_nick.to_string()).to_string();
                                 //let oauth = oauth_iter.next().unwrap_or("");
                                 
-                            if app.stream_mgr.connect(tw_serv.to_string().clone(), tw_serv.to_owned() + tw_port, net_tx.clone(), tw_nick.clone(), app.real.clone(), oauth.to_string()).await {
+                            let info = ConnectInfo {
+                                addr: tw_serv.to_owned() + tw_port,
+                                nick: tw_nick.clone(),
+                                real: app.real.clone(),
+                                oauth: oauth.to_string(),
+                                tls: true,
+                                auto_reconnect: true,
+                                flood_burst,
+                                flood_rate,
+                                ..Default::default()
+                            };
+                            if app.stream_mgr.connect(tw_serv.to_string().clone(), info, net_tx.clone()).await {
                                 match app.server_list.entry(tw_serv.to_string().clone()) {
-                                    Entry::Occupied(o) => o.into_mut(),
+                                    Entry::Occupied(o) => {
+                                        let server = o.into_mut();
+                                        server.caps.reset();
+                                        server
+                                    }
                                     Entry::Vacant(v) => {
                                     // Create a new HashMap with the "Status" channel already inserted
                                         let mut channels = BTreeMap::new();
-                                        channels.insert("Status".to_string(), ChannelData::default());
+                                        channels.insert(IrcKey::new("Status", CaseMapping::default()), ChannelData::new());
                                         v.insert(ServerData {
                                             channels,
                                             nick: tw_nick.clone(),
+                                            ..Default::default()
                                         })
                                     }
                                 };
@@ -435,8 +496,8 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                                 app.active_channel = "Status".to_string();
                                 app.active_nick = tw_nick.to_string();
                                 if let Some(server) =  app.server_list.get_mut(&mut app.active_server) {
-                                    if let Some(channel) = server.channels.get_mut(&app.active_channel) {
-                                        channel.chat_list.push(("System".to_string(), String::from(format!("<connecting to {}>", tw_serv.to_owned() + tw_port))));
+                                    if let Some(channel) = server.channel_mut(&app.active_channel) {
+                                        channel.chat_list.push(ChatLine::system(&format!("<connecting to {}>", tw_serv.to_owned() + tw_port)));
                                     }
                                 }
                             }
@@ -448,40 +509,60 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                             //}
                     }
                     s if s.to_uppercase().starts_with("/CONNECT") => {
-                        let port = ":6667".to_string();
+                        let mut port = ":6667".to_string();
                         let mut server_id: String = "".to_string();
                         let mut addr:String = "".to_string();
-                        let re = Regex::new(r"^/connect\s+([^\s:]+)(?::(\d+))?$").unwrap();
+                        let mut tls = false;
+                        let re = Regex::new(r"^/connect\s+(-tls\s+)?([^\s:]+)(?::(\d+))?$").unwrap();
                         if re.is_match(&line) {
                             let conn = re.captures(&line).unwrap();
-                            let serv_group = conn.get(1).map_or("", |m| m.as_str());
-                            let port_group = conn.get(2).map_or("", |m| m.as_str());
+                            tls = conn.get(1).is_some();
+                            let serv_group = conn.get(2).map_or("", |m| m.as_str());
+                            let port_group = conn.get(3).map_or("", |m| m.as_str());
                             server_id = serv_group.to_string();
+                            if tls {
+                                port = ":6697".to_string();
+                            }
                             if port_group.is_empty() {
                                 addr = serv_group.to_string() + &port;
                             } else {
                                 addr = serv_group.to_string() + ":" + &port_group;
                             }
                         }
- 
-                        if app.stream_mgr.connect(server_id.clone(), addr.to_string(), net_tx.clone(), app.active_nick.clone(), app.real.clone(), "".to_string()).await {
+
+                        let info = ConnectInfo {
+                            addr: addr.clone(),
+                            nick: app.active_nick.clone(),
+                            real: app.real.clone(),
+                            tls,
+                            auto_reconnect: true,
+                            flood_burst: FLOOD_BURST,
+                            flood_rate: FLOOD_RATE,
+                            ..Default::default()
+                        };
+                        if app.stream_mgr.connect(server_id.clone(), info, net_tx.clone()).await {
                             match app.server_list.entry(server_id.clone()) {
-                                Entry::Occupied(o) => o.into_mut(),
+                                Entry::Occupied(o) => {
+                                    let server = o.into_mut();
+                                    server.caps.reset();
+                                    server
+                                }
                                 Entry::Vacant(v) => {
                                 // Create a new HashMap with the "Status" channel already inserted
                                     let mut channels = BTreeMap::new();
-                                    channels.insert("Status".to_string(), ChannelData::default());
+                                    channels.insert(IrcKey::new("Status", CaseMapping::default()), ChannelData::new());
                                     v.insert(ServerData {
                                         channels,
                                         nick: app.active_nick.clone(),
+                                        ..Default::default()
                                     })
                                 }
                             };
                             app.active_server = server_id.clone();
                             app.active_channel = "Status".to_string();
                             if let Some(server) =  app.server_list.get_mut(&mut app.active_server) {
-                                if let Some(channel) = server.channels.get_mut(&app.active_channel) {
-                                    channel.chat_list.push(("System".to_string(),String::from(format!("<connecting to {}>", addr))));
+                                if let Some(channel) = server.channel_mut(&app.active_channel) {
+                                    channel.chat_list.push(ChatLine::system(&format!("<connecting to {}>", addr)));
                                 }
                             }
                         } 
@@ -489,87 +570,154 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                     s if s.to_uppercase().starts_with("/QUIT") => {
                         app.quit();
                     }
+                    s if s.to_uppercase().starts_with("/RECONNECT") => {
+                        if !app.stream_mgr.reconnect(&app.active_server) {
+                            app.chat_bounds(ChatLine::error("Error Not Connected to a server"), app.active_server.clone(), app.active_channel.clone());
+                        }
+                    }
                     s if s.to_uppercase().starts_with("/DISCONNECT ") => {
                         let server_id = &line[12..];
                         if app.server_list.contains_key(server_id) {
                             app.stream_mgr.disconnect(server_id);
                             app.server_list.remove(server_id);
                             //app.chat_bounds(server_id.to_string(), "System".to_string(), "Status".to_string(), "test".to_string());
-                            let (on, left_server, left_chan, right_server, right_chan) = app.split.clone();
-                            if on {
-                                if server_id == left_server {
-                                    if app.active_server == left_server {
-                                        app.split = (true, "System".to_string(), "Status".to_string(), right_server.clone(), right_chan.clone());
-                                        app.active_server = "System".to_string();
-                                        app.active_channel = "Status".to_string();
-                                    } else {
-                                        app.split = (true, "System".to_string(), "Status".to_string(), right_server.clone(), right_chan.clone());
-                                    }
-                                } else if server_id == right_server {
-                                    if app.active_server == right_server {
-                                        app.split = (true, left_server, left_chan, "System".to_string(), "Status".to_string());
-                                        app.active_server = "System".to_string();
-                                        app.active_channel = "Status".to_string();
-                                    } else  {
-                                        app.split = (true, left_server, left_chan, "System".to_string(), "Status".to_string());
-                                    }
-                                }
-                            } else {
-                                if app.active_server == server_id {
-                                    app.active_server = "System".to_string();
-                                    app.active_channel = "Status".to_string();
-                                }
-                            }
+                            //Panes of the server show Status instead
+                            app.close_panes(|server, _| server == server_id);
                         }                        
                     }
-                    s if s.to_uppercase().starts_with("/JOIN #") => {
+                    s if s.to_uppercase().starts_with("/JOIN ") => {
                         let prompt_command = line[1..line.len()].to_owned();
                         if app.active_server!= "System" {
+                            //Remember channel keys so the channels can be rejoined after a reconnect
+                            let mut args = prompt_command.split_whitespace().skip(1);
+                            let chans = args.next().unwrap_or("");
+                            let keys = args.next().unwrap_or("");
+                            if let Some(server) = app.server_list.get_mut(&app.active_server) {
+                                for (chan, key) in chans.split(',').zip(keys.split(',')) {
+                                    if !key.is_empty() {
+                                        let chan = server.key(chan);
+                                        server.join_keys.insert(chan, key.to_string());
+                                    }
+                                }
+                                for chan in chans.split(',').filter(|c| !c.is_empty()) {
+                                    let chan = server.key(chan);
+                                    server.joining.insert(chan);
+                                }
+                            }
                             app.stream_mgr.send_line(app.active_server.clone(), prompt_command);
                         } else {
-                            app.chat_bounds("Error Not Connected to a server".to_owned(), "System".to_string(), "Status".to_string(), "ERROR".to_string());
+                            app.chat_bounds(ChatLine::error("Error Not Connected to a server"), "System".to_string(), "Status".to_string());
                         }
                     }
                     s if s.to_uppercase().starts_with("/PART") => {
                         let prompt_write = line[1..].to_string();
-                        if line.contains('#') {
+                        //A channel we were kicked from only needs its buffer closed
+                        let is_channel = app.server_list.get(&app.active_server).is_some_and(|s| s.isupport.is_channel(line[5..].trim()) && !s.channel(line[5..].trim()).is_some_and(|c| c.parted));
+                        if is_channel {
                             app.stream_mgr.send_line(app.active_server.clone(), prompt_write);
                         }
                         if let Some(server) = app.server_list.get_mut(&app.active_server) {
-                            if server.channels.contains_key(&line[6..]) {
-                                server.channels.remove(&line[6..]);
+                            let key = server.key(&line[6..]);
+                            if server.channels.contains_key(&key) {
+                                server.channels.remove(&key);
+                                server.join_keys.remove(&key);
                                 
-                                let (on, left_server, left_chan, right_server, right_chan) = app.split.clone();
-                                if on {
-                                    if app.active_channel == &line[6..] {
-                                        app.split = (true, "System".to_string(), "Status".to_string(), right_server, right_chan);
-                                        app.active_server = "System".to_string();
-                                        app.active_channel = "Status".to_string();
-                                    } else if app.active_channel == right_chan {
-                                        app.split = (true, left_server, left_chan, "System".to_string(), "Status".to_string());
-                                        app.active_server = "System".to_string();
-                                        app.active_channel = "Status".to_string();
-                                    }
-                                } else {
-                                    if app.active_channel == &line[6..] {
-                                        app.active_server = "System".to_string();
-                                        app.active_channel = "Status".to_string();
-                                }
-                            }
-                                //app.active_server = "System".to_string();
-                                //app.active_channel = "Status".to_string();
+                                //Panes of the channel show Status instead
+                                let server_id = app.active_server.clone();
+                                app.close_panes(|server, channel| server == server_id && key.matches(channel));
                             } else {
-                                app.chat_bounds("Channel Not Joined".to_string(), app.active_server.clone(), app.active_channel.clone(), "Error".to_string())
+                                app.chat_bounds(ChatLine::error("Channel Not Joined"), app.active_server.clone(), app.active_channel.clone())
                             }
                         }
                     }
                     s if s.to_uppercase().starts_with("/NICK") => {
-                        if app.active_server != "System" {
+                        let nicklen = app.server_list.get(&app.active_server).and_then(|s| s.isupport.nicklen);
+                        if nicklen.is_some_and(|max| line[5..].trim().len() > max) {
+                            app.chat_bounds(ChatLine::error(&format!("Nick is longer than the server allows ({} characters)", nicklen.unwrap_or(0))), app.active_server.clone(), app.active_channel.clone());
+                        } else if app.active_server != "System" {
                             let prompt_command = line[1..line.len()].to_owned();
                             app.stream_mgr.send_line(app.active_server.clone(), prompt_command);
                         } else {
                             app.active_nick = line[6..].to_string();
-                            app.chat_bounds("You're now known as ".to_owned() + &app.active_nick, "System".to_string(), "Status".to_string(), "NICK".to_string())
+                            app.chat_bounds(ChatLine::new(LineKind::Nick, "NICK", &format!("You're now known as {}", app.active_nick)), "System".to_string(), "Status".to_string())
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/MODE") => {
+                        //Active channel unless the first argument is a channel or nick
+                        let args: Vec<&str> = line.split_whitespace().skip(1).collect();
+                        let prompt_write = match args.first() {
+                            Some(first) if !first.starts_with(['+', '-']) => format!("MODE {}", args.join(" ")),
+                            _ => format!("MODE {} {}", app.active_channel, args.join(" ")),
+                        };
+                        if app.active_server != "System" {
+                            app.stream_mgr.send_line(app.active_server.clone(), prompt_write.trim_end().to_string());
+                        } else {
+                            app.chat_bounds(ChatLine::error("Error Not Connected to a server"), "System".to_string(), "Status".to_string());
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/OP ") => {
+                        let nicks: Vec<String> = line[4..].split_whitespace().map(|n| n.to_string()).collect();
+                        channel_modes(app, "+o", nicks);
+                    }
+                    s if s.to_uppercase().starts_with("/DEOP ") => {
+                        let nicks: Vec<String> = line[6..].split_whitespace().map(|n| n.to_string()).collect();
+                        channel_modes(app, "-o", nicks);
+                    }
+                    s if s.to_uppercase().starts_with("/VOICE ") => {
+                        let nicks: Vec<String> = line[7..].split_whitespace().map(|n| n.to_string()).collect();
+                        channel_modes(app, "+v", nicks);
+                    }
+                    s if s.to_uppercase().starts_with("/BAN ") => {
+                        let masks: Vec<String> = line[5..].split_whitespace().map(|n| ban_mask(app, n)).collect();
+                        channel_modes(app, "+b", masks);
+                    }
+                    s if s.to_uppercase().starts_with("/UNBAN ") => {
+                        let masks: Vec<String> = line[7..].split_whitespace().map(|n| ban_mask(app, n)).collect();
+                        channel_modes(app, "-b", masks);
+                    }
+                    s if s.to_uppercase().starts_with("/KICK ") => {
+                        //KICK <channel> <nick> [:reason]
+                        let mut args = line[6..].trim().splitn(2, ' ');
+                        let nick = args.next().unwrap_or("");
+                        let reason = args.next().unwrap_or("").trim();
+                        if let Some(channel) = active_channel(app) {
+                            let prompt_write = if reason.is_empty() { format!("KICK {} {}", channel, nick) } else { format!("KICK {} {} :{}", channel, nick, reason) };
+                            app.stream_mgr.send_line(app.active_server.clone(), prompt_write);
+                        } else {
+                            app.chat_bounds(ChatLine::error("Error Not in a channel"), app.active_server.clone(), app.active_channel.clone());
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/ME ") => {
+                        if app.active_server != "System" && app.active_channel != "Status" {
+                            let target = app.active_channel.clone();
+                            send_action(app, &target, &line[4..]);
+                            if !echo_message(app) {
+                                app.chat_bounds(ChatLine::new(LineKind::Action, &app.active_nick, &line[4..]), app.active_server.clone(), target);
+                            }
+                        } else {
+                            app.chat_bounds(ChatLine::error("Error Not in a channel or query"), app.active_server.clone(), app.active_channel.clone());
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/CTCP ") => {
+                        //ctcp nick COMMAND [args], PING gets a timestamp so the reply shows the round trip
+                        let mut args = line[6..].trim().splitn(3, ' ');
+                        let nick = args.next().unwrap_or("").to_string();
+                        let command = args.next().unwrap_or("VERSION").to_uppercase();
+                        let mut params = args.next().unwrap_or("").to_string();
+                        if command == "PING" && params.is_empty() {
+                            params = ctcp::ping_token();
+                        }
+                        if app.active_server != "System" && !nick.is_empty() {
+                            app.stream_mgr.send_line(app.active_server.clone(), format!("PRIVMSG {} :{}", nick, ctcp::encode(&command, &params)));
+                            app.chat_bounds(ChatLine::new(LineKind::System, "CTCP", &format!("CTCP {} sent to {}", command, nick)), app.active_server.clone(), app.active_channel.clone());
+                        } else {
+                            app.chat_bounds(ChatLine::error("Error Not Connected, or no nick given"), app.active_server.clone(), app.active_channel.clone());
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/SEARCH ") => {
+                        //search <regex>, matches of every buffer in a popup, Enter goes to the selected one
+                        if let Err(e) = search::search_all(app, line[8..].trim()) {
+                            app.chat_bounds(ChatLine::error(&format!("Invalid search: {}", e)), app.active_server.clone(), app.active_channel.clone());
                         }
                     }
                     s if s.to_uppercase().starts_with("/MSG") => {
@@ -601,40 +749,20 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                                 }
                             }
 
-                            let prompt_write = "PRIVMSG ".to_owned() + &nick.unwrap_or("") + " :" + &msg;
-                            app.stream_mgr.send_line(app.active_server.clone(), prompt_write);
-                            app.chat_bounds(msg.clone(), app.active_server.clone(), app.active_channel.clone(), app.active_nick.clone());
-                            app.chat_bounds(msg.clone(), app.active_server.clone(), nick.unwrap_or("").to_string(), app.active_nick.clone());
+                            send_message(app, nick.unwrap_or(""), &msg);
+                            app.chat_copy(ChatLine::new(LineKind::Message, &app.active_nick, &msg), app.active_server.clone(), app.active_channel.clone());
+                            if !echo_message(app) {
+                                app.chat_bounds(ChatLine::new(LineKind::Message, &app.active_nick, &msg), app.active_server.clone(), nick.unwrap_or("").to_string());
+                            }
                         } else {
-                            app.chat_bounds("Error Not Connected, or wrong server".to_owned(), "System".to_string(), "Status".to_string(), "Error".to_string())
+                            app.chat_bounds(ChatLine::error("Error Not Connected, or wrong server"), "System".to_string(), "Status".to_string())
                         }
 
                     }
                     s if s.to_uppercase().starts_with("/SWAP") => {
                         let swap_nr: &str = &s[6..];
                         if let Ok(nr) = swap_nr.parse::<usize>() {
-                            let mut channel_lines: (Vec<String>, Vec<String>) = (Vec::new(), Vec::new());
-
-                            for (outer_key, inner_map) in &app.server_list {
-                                //channel_lines.push(Line::from(outer_key.to_owned()));
-                                for (inner_key, _data) in &inner_map.channels {
-                                    channel_lines.1.push(format!("{}", inner_key));
-                                    channel_lines.0.push(format!("{}", outer_key));
-                                }
-
-                            }
-                            if nr < channel_lines.0.len() {
-                                app.split = (false, String::new(),String::new(),String::new(),String::new());
-                                app.active_server = channel_lines.0[nr].clone();
-                                app.active_channel = channel_lines.1[nr].clone();
-                                if let Some(server) = app.server_list.get_mut(&channel_lines.0[nr].clone()) {
-                                    app.active_nick = server.nick.clone();
-                                    if let Some(channel) = server.channels.get_mut(&channel_lines.1[nr].clone()) {
-                                        channel.chat_pos = 0;
-                                        channel.notification = false;
-                                    }
-                                }
-                            }
+                            app.switch_number(nr);
                         }
                     }
                     s if s.to_uppercase().starts_with("/SPLIT") => {
@@ -643,33 +771,107 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                         let nr_right: &str = &s[center_byte.unwrap_or(0)+1..];
  
                         if let (Ok(left), Ok(right)) = (nr_left.parse::<usize>(), nr_right.parse::<usize>()) {
-                            let mut channel_lines: (Vec<String>, Vec<String>) = (Vec::new(), Vec::new());
-
-                            for (outer_key, inner_map) in &app.server_list {
-                                for (inner_key, _data) in &inner_map.channels {
-                                    channel_lines.1.push(format!("{}",inner_key));
-                                    channel_lines.0.push(format!("{}",outer_key));
-                                }
-                            }
-                            if left < channel_lines.0.len() && right < channel_lines.0.len() {
-                                app.split = (true, channel_lines.0[left].clone(), channel_lines.1[left].clone(), channel_lines.0[right].clone(), channel_lines.1[right].clone());
-                                app.active_server = channel_lines.0[left].clone();
-                                app.active_channel = channel_lines.1[left].clone();
-                                if let Some(server) = app.server_list.get_mut(&channel_lines.0[left].clone()) {
-                                    app.active_nick = server.nick.clone();
-                                    if let Some(channel) = server.channels.get_mut(&channel_lines.1[left].clone()) {
+                            let buffers = app.buffer_list();
+                            if let (Some((left_server, left_chan)), Some((right_server, right_chan))) = (buffers.get(left), buffers.get(right)) {
+                                //Replaces the whole layout with two panes side by side
+                                app.layout.set(Node::Split {
+                                    split: SplitDir::Vertical,
+                                    ratio: 50,
+                                    first: Box::new(Node::pane(left_server, left_chan)),
+                                    second: Box::new(Node::pane(right_server, right_chan)),
+                                });
+                                app.focus_changed();
+                                for (server_id, channel_id) in [(left_server, left_chan), (right_server, right_chan)] {
+                                    if let Some(channel) = app.server_list.get_mut(server_id).and_then(|s| s.channel_mut(channel_id)) {
                                         channel.chat_pos = 0;
-                                        channel.notification = false;
+                                        channel.mark_read();
                                     }
                                 }
-                                if let Some(server) = app.server_list.get_mut(&channel_lines.0[right].clone()) {
-                                    if let Some(channel) = server.channels.get_mut(&channel_lines.1[right].clone()) {
-                                        channel.chat_pos = 0;
-                                        channel.notification = false;
-                                    }
+                            }
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/VSPLIT") || s.to_uppercase().starts_with("/HSPLIT") => {
+                        //vsplit/hsplit <number>, the focused pane is split and the new half shows that buffer, or the same one without a number
+                        let dir = if s.to_uppercase().starts_with("/V") { SplitDir::Vertical } else { SplitDir::Horizontal };
+                        let arg = s[7..].trim();
+                        let buffer = if arg.is_empty() {
+                            Some((app.active_server.clone(), app.active_channel.clone()))
+                        } else {
+                            arg.parse::<usize>().ok().and_then(|nr| app.buffer_list().get(nr).cloned())
+                        };
+                        match buffer {
+                            Some((server_id, channel_id)) => {
+                                app.layout.split(dir, &server_id, &channel_id);
+                                app.focus_changed();
+                            }
+                            None => app.chat_bounds(ChatLine::error("No channel with that number"), app.active_server.clone(), app.active_channel.clone()),
+                        }
+                    }
+                    s if s.to_uppercase() == "/CLOSE" => {
+                        if app.layout.close() {
+                            app.focus_changed();
+                        } else {
+                            app.chat_bounds(ChatLine::error("The last pane can not be closed"), app.active_server.clone(), app.active_channel.clone());
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/RESIZE ") => {
+                        //resize +10 or -10, percent the focused pane grows or shrinks within its split
+                        let resized = s[8..].trim().parse::<i32>().is_ok_and(|delta| app.layout.resize(delta));
+                        if !resized {
+                            app.chat_bounds(ChatLine::error("Usage /resize +n or -n in a split view"), app.active_server.clone(), app.active_channel.clone());
+                        }
+                    }
+                    s if s.to_uppercase().starts_with("/FOCUS ") => {
+                        if s[7..].trim().parse::<usize>().is_ok_and(|nr| app.layout.focus(nr)) {
+                            app.focus_changed();
+                        } else {
+                            app.chat_bounds(ChatLine::error("No pane with that number"), app.active_server.clone(), app.active_channel.clone());
+                        }
+                    }
+                    s if s.to_uppercase() == "/LAYOUT" || s.to_uppercase().starts_with("/LAYOUT ") => {
+                        //layout lists the named layouts, layout <name> restores one, layout save <name> saves the current one
+                        let args: Vec<&str> = s[7..].split_whitespace().collect();
+                        match args.as_slice() {
+                            [] => {
+                                let names: Vec<&str> = app.layouts.keys().map(|n| n.as_str()).collect();
+                                app.chat_bounds(ChatLine::system(&format!("Layouts: {}", names.join(" "))), app.active_server.clone(), app.active_channel.clone());
+                            }
+                            [save, name] if save.eq_ignore_ascii_case("save") => {
+                                let result = layout::save(name, &app.layout.root);
+                                app.layouts.insert(name.to_string(), app.layout.root.clone());
+                                match result {
+                                    Ok(()) => app.chat_bounds(ChatLine::system(&format!("Layout {} saved", name)), app.active_server.clone(), app.active_channel.clone()),
+                                    Err(e) => app.chat_bounds(ChatLine::error(&format!("Layout {} not saved: {}", name, e)), app.active_server.clone(), app.active_channel.clone()),
                                 }
-
                             }
+                            [name] => {
+                                if !app.restore_layout(name) {
+                                    app.chat_bounds(ChatLine::error(&format!("No layout named {}", name)), app.active_server.clone(), app.active_channel.clone());
+                                }
+                            }
+                            _ => app.chat_bounds(ChatLine::error("Usage /layout, /layout <name> or /layout save <name>"), app.active_server.clone(), app.active_channel.clone()),
+                        }
+                    }
+                    s if s.to_uppercase() == "/NICKLIST" || s.to_uppercase().starts_with("/NICKLIST ") => {
+                        //nicklist toggles it, nicklist <width> resizes and shows it
+                        match s[9..].trim().parse::<u16>() {
+                            Ok(width) if width >= 8 => {
+                                app.nicklist_width = width;
+                                app.nicklist = true;
+                            }
+                            Ok(_) => app.chat_bounds(ChatLine::error("Nick list width has to be at least 8"), app.active_server.clone(), app.active_channel.clone()),
+                            Err(_) => app.nicklist = !app.nicklist,
+                        }
+                    }
+                    s if s.to_uppercase() == "/SIDEBAR" || s.to_uppercase().starts_with("/SIDEBAR ") => {
+                        //sidebar toggles it, sidebar <width> resizes and shows it
+                        match s[8..].trim().parse::<u16>() {
+                            Ok(width) if width >= 8 => {
+                                app.sidebar_width = width;
+                                app.sidebar = true;
+                            }
+                            Ok(_) => app.chat_bounds(ChatLine::error("Sidebar width has to be at least 8"), app.active_server.clone(), app.active_channel.clone()),
+                            Err(_) => app.sidebar = !app.sidebar,
                         }
                     }
                     s if s.to_uppercase().starts_with("/") => {
@@ -679,7 +881,7 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                     _ => {
                         // process line, send, etc
                         if app.active_server != "System" {
-                            let mut result = String::from(line);
+                            let mut result = line.clone();
                             while let Some(start) = result.find("\\u{") {
                                 if let Some(end) = result[start..].find('}') {
                                     let end = start + end + 1;
@@ -700,11 +902,13 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
                                     break;
                                 }
                             }
-                            let prompt_write = "PRIVMSG ".to_owned() + &app.active_channel + " :" + &result;
-                            app.stream_mgr.send_line(app.active_server.clone(), prompt_write);
-                            app.chat_bounds(result.clone(), app.active_server.clone(), app.active_channel.clone(), app.active_nick.clone())
+                            let target = app.active_channel.clone();
+                            send_message(app, &target, &result);
+                            if !echo_message(app) {
+                                app.chat_bounds(ChatLine::new(LineKind::Message, &app.active_nick, &result), app.active_server.clone(), app.active_channel.clone())
+                            }
                         } else {
-                            app.chat_bounds("Error currently not connected to a server or in a channel".to_owned(), "System".to_owned(), "Status".to_owned(), "Error".to_string());
+                            app.chat_bounds(ChatLine::error("Error currently not connected to a server or in a channel"), "System".to_owned(), "Status".to_owned());
                         }
                     }
                 }
@@ -713,8 +917,102 @@ pub async fn handle_input(app: &mut App, ev: AppEvent, net_tx: &tokio::sync::mps
             app.prompt.clear();
             app.input_mode.clear();
             app.input_mode.push(Span::from("N"));
-            app.popup = Popup::None;
+            //Search results stay open for Up/Down and Enter
+            if !line.to_uppercase().starts_with("/SEARCH ") {
+                app.popup = Popup::None;
+            }
         }
     }
 }
 
+// Active channel name when the active buffer is a channel on a connected server
+fn active_channel(app: &App) -> Option<String> {
+    app.server_list.get(&app.active_server)
+        .filter(|s| s.isupport.is_channel(&app.active_channel))
+        .map(|_| app.active_channel.clone())
+}
+
+// MODE #chan +ooo a b c, with as many targets per line as the server's MODES allows
+fn channel_modes(app: &mut App, change: &str, targets: Vec<String>) {
+    let Some(channel) = active_channel(app) else {
+        app.chat_bounds(ChatLine::error("Error Not in a channel"), app.active_server.clone(), app.active_channel.clone());
+        return;
+    };
+    let per_line = app.server_list.get(&app.active_server).map_or(3, |s| s.isupport.modes.max(1));
+    let (sign, mode) = change.split_at(1);
+    for batch in targets.chunks(per_line) {
+        let prompt_write = format!("MODE {} {}{} {}", channel, sign, mode.repeat(batch.len()), batch.join(" "));
+        app.stream_mgr.send_line(app.active_server.clone(), prompt_write);
+    }
+}
+
+// Masks are used as given, a nick becomes *!*@host when its host is known and nick!*@* otherwise
+fn ban_mask(app: &App, target: &str) -> String {
+    if target.contains(['!', '@', '*']) {
+        return target.to_string();
+    }
+    let host = app.server_list.get(&app.active_server).and_then(|s| {
+        let key = s.key(target);
+        s.channel(&app.active_channel).and_then(|c| c.members.get(&key)).and_then(|m| m.host.clone())
+    });
+    match host {
+        Some(host) => format!("*!*@{}", host),
+        None => format!("{}!*@*", target),
+    }
+}
+
+// PRIVMSG split into as many lines as needed to fit the 512 byte limit
+fn send_message(app: &App, target: &str, text: &str) {
+    let room = app.server_list.get(&app.active_server).map_or(400, |s| s.message_room("PRIVMSG", target));
+    for piece in textstyle::split_message(text, room) {
+        app.stream_mgr.send_line(app.active_server.clone(), format!("PRIVMSG {} :{}", target, piece));
+    }
+}
+
+// /me, split like a message with every piece wrapped as a CTCP ACTION
+fn send_action(app: &App, target: &str, text: &str) {
+    let wrapper = ctcp::encode("ACTION", "").len() + 1;
+    let room = app.server_list.get(&app.active_server).map_or(400, |s| s.message_room("PRIVMSG", target)).saturating_sub(wrapper);
+    for piece in textstyle::split_message(text, room) {
+        app.stream_mgr.send_line(app.active_server.clone(), format!("PRIVMSG {} :{}", target, ctcp::encode("ACTION", &piece)));
+    }
+}
+
+// Server echoes our own messages back, so they are shown when they arrive
+fn echo_message(app: &App) -> bool {
+    app.server_list.get(&app.active_server).is_some_and(|s| s.caps.has("echo-message"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn msg_from_channel_is_not_saved_in_its_scrollback() {
+        let mut app = App::default();
+        app.scrollback.lines = 100;
+        let mut stored = app.scrollback.capture();
+        let mut channels = BTreeMap::new();
+        for name in ["Status", "#rust"] {
+            channels.insert(IrcKey::new(name, CaseMapping::default()), ChannelData::new());
+        }
+        app.server_list.insert("irc.test".to_string(), ServerData { channels, nick: "me".to_string(), ..Default::default() });
+        app.active_server = "irc.test".to_string();
+        app.active_channel = "#rust".to_string();
+        app.active_nick = "me".to_string();
+        app.prompt = "/msg alice secret".to_string();
+        let (net_tx, _net_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        handle_input(&mut app, AppEvent::InputSend, &net_tx).await;
+
+        let mut files = Vec::new();
+        while let Ok(entry) = stored.try_recv() {
+            files.push(entry.file);
+        }
+        //Shown in #rust, saved only in the query
+        let rust = app.server_list["irc.test"].channel("#rust").unwrap();
+        assert!(rust.chat_list.iter().any(|l| l.text.contains("secret")));
+        assert!(!files.iter().any(|f| f.ends_with("#rust.jsonl")));
+        assert!(files.iter().any(|f| f.ends_with("alice.jsonl")));
+    }
+}
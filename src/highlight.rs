@@ -0,0 +1,82 @@
+// highlight.rs
+use std::collections::HashMap;
+use chrono::{DateTime, Local};
+use regex::{Regex, RegexBuilder};
+use crate::casemap::CaseMapping;
+
+pub const MAX_MENTIONS: usize = 200;
+
+// Extra words and patterns that count as a mention, besides our own nick
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    pub words: Vec<String>,
+    pub patterns: Vec<Regex>,
+}
+
+impl RuleSet {
+    fn matches(&self, text: &str) -> bool {
+        let lower = text.to_lowercase();
+        self.words.iter().any(|w| contains_word(&lower, &w.to_lowercase(), |c| c.is_alphanumeric()))
+            || self.patterns.iter().any(|p| p.is_match(text))
+    }
+}
+
+// Global rules and rules for one channel name on any server, names are compared in the casemapping of the server
+#[derive(Debug, Default)]
+pub struct HighlightRules {
+    pub global: RuleSet,
+    pub channels: HashMap<String, RuleSet>,
+}
+
+impl HighlightRules {
+    // Words and regexes from the config, for a channel or everywhere, returns the patterns that did not compile
+    pub fn add(&mut self, channel: Option<&str>, words: &[String], patterns: &[String]) -> Vec<String> {
+        let rules = match channel {
+            Some(channel) => self.channels.entry(channel.to_string()).or_default(),
+            None => &mut self.global,
+        };
+        rules.words.extend(words.iter().filter(|w| !w.is_empty()).cloned());
+        let mut invalid = Vec::new();
+        for pattern in patterns {
+            match RegexBuilder::new(pattern).case_insensitive(true).build() {
+                Ok(regex) => rules.patterns.push(regex),
+                Err(_) => invalid.push(pattern.clone()),
+            }
+        }
+        invalid
+    }
+
+    // Our nick as a whole word, or a global rule, or a rule of this channel
+    pub fn is_highlight(&self, nick: &str, channel: &str, text: &str, mapping: CaseMapping) -> bool {
+        if !nick.is_empty() && contains_word(&mapping.fold(text), &mapping.fold(nick), is_nick_char) {
+            return true;
+        }
+        self.global.matches(text) || self.channels.iter().any(|(name, rules)| mapping.equal(name, channel) && rules.matches(text))
+    }
+}
+
+// A highlighted line for the mentions popup
+#[derive(Clone, Debug)]
+pub struct Mention {
+    pub server: String,
+    pub channel: String,
+    pub time: DateTime<Local>,
+    pub sender: String,
+    pub text: String,
+}
+
+fn is_nick_char(c: char) -> bool {
+    c.is_alphanumeric() || "[]\\`_^{|}-".contains(c)
+}
+
+// word in text with no word character right before or after it, so "rust" does not match "rusty"
+fn contains_word(text: &str, word: &str, is_word_char: impl Fn(char) -> bool) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+    text.match_indices(word).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(&is_word_char) && !after.is_some_and(&is_word_char)
+    })
+}
@@ -0,0 +1,143 @@
+// isupport.rs
+use std::collections::BTreeMap;
+use crate::casemap::CaseMapping;
+
+// Server features from RPL_ISUPPORT (005), defaults are what RFC 1459 servers use
+#[derive(Clone, Debug)]
+pub struct ISupport {
+    // Every token as sent, name -> value
+    pub tokens: BTreeMap<String, String>,
+    pub chantypes: String,
+    // (mode, symbol) highest rank first, (o, @) (v, +)
+    pub prefix: Vec<(char, char)>,
+    // A list modes, B always take a parameter, C only when set, D never
+    pub chanmodes: [String; 4],
+    pub casemapping: CaseMapping,
+    pub nicklen: Option<usize>,
+    pub topiclen: Option<usize>,
+    pub network: Option<String>,
+    // Modes with a parameter allowed in one MODE command
+    pub modes: usize,
+    // Command -> max targets, None means no limit
+    pub targmax: BTreeMap<String, Option<usize>>,
+    // Most messages one CHATHISTORY request returns, 0 means no limit
+    pub chathistory: Option<usize>,
+}
+
+impl Default for ISupport {
+    fn default() -> Self {
+        ISupport {
+            tokens: BTreeMap::new(),
+            chantypes: "#&".to_string(),
+            prefix: vec![('o', '@'), ('v', '+')],
+            chanmodes: ["b".to_string(), "k".to_string(), "l".to_string(), "imnpst".to_string()],
+            casemapping: CaseMapping::Rfc1459,
+            nicklen: None,
+            topiclen: None,
+            network: None,
+            modes: 3,
+            targmax: BTreeMap::new(),
+            chathistory: None,
+        }
+    }
+}
+
+impl ISupport {
+    // 005 <client> <token>... :are supported by this server
+    pub fn apply(&mut self, params: &[String]) {
+        let count = params.len().saturating_sub(1);
+        for token in params.iter().take(count).skip(1) {
+            if let Some(name) = token.strip_prefix('-') {
+                self.tokens.remove(name);
+                self.set(name, None);
+            } else {
+                let (name, value) = token.split_once('=').unwrap_or((token, ""));
+                self.tokens.insert(name.to_string(), value.to_string());
+                self.set(name, Some(value));
+            }
+        }
+    }
+
+    // A token was added or removed, None puts the default back
+    fn set(&mut self, name: &str, value: Option<&str>) {
+        let default = ISupport::default();
+        match name {
+            "CHANTYPES" => self.chantypes = value.map_or(default.chantypes, |v| v.to_string()),
+            "PREFIX" => {
+                //PREFIX=(qaohv)~&@%+
+                self.prefix = value
+                    .and_then(|v| v.strip_prefix('('))
+                    .and_then(|v| v.split_once(')'))
+                    .map_or(default.prefix, |(modes, symbols)| modes.chars().zip(symbols.chars()).collect());
+            }
+            "CHANMODES" => {
+                self.chanmodes = default.chanmodes;
+                if let Some(value) = value {
+                    for (i, group) in value.split(',').take(4).enumerate() {
+                        self.chanmodes[i] = group.to_string();
+                    }
+                }
+            }
+            "CASEMAPPING" => self.casemapping = value.map_or(default.casemapping, CaseMapping::parse),
+            "NICKLEN" => self.nicklen = value.and_then(|v| v.parse().ok()),
+            "TOPICLEN" => self.topiclen = value.and_then(|v| v.parse().ok()),
+            "NETWORK" => self.network = value.filter(|v| !v.is_empty()).map(|v| v.to_string()),
+            "MODES" => self.modes = value.and_then(|v| v.parse().ok()).unwrap_or(default.modes),
+            "CHATHISTORY" => self.chathistory = value.and_then(|v| v.parse().ok()),
+            "TARGMAX" => {
+                //TARGMAX=PRIVMSG:4,NOTICE:4,JOIN:
+                self.targmax.clear();
+                for entry in value.unwrap_or("").split(',').filter(|e| !e.is_empty()) {
+                    let (command, max) = entry.split_once(':').unwrap_or((entry, ""));
+                    self.targmax.insert(command.to_uppercase(), max.parse().ok());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_channel(&self, name: &str) -> bool {
+        name.chars().next().is_some_and(|c| self.chantypes.contains(c))
+    }
+
+    // Nick from a NAMES entry without its @/+ symbols
+    pub fn strip_prefix<'a>(&self, name: &'a str) -> &'a str {
+        name.trim_start_matches(|c| self.prefix.iter().any(|(_, symbol)| *symbol == c))
+    }
+
+    // Mode for a NAMES symbol, @ -> o
+    pub fn prefix_mode(&self, symbol: char) -> Option<char> {
+        self.prefix.iter().find(|(_, s)| *s == symbol).map(|(mode, _)| *mode)
+    }
+
+    pub fn is_prefix_mode(&self, mode: char) -> bool {
+        self.prefix.iter().any(|(m, _)| *m == mode)
+    }
+
+    // Split a MODE change into (adding, mode, parameter), PREFIX and CHANMODES tell which modes take a parameter
+    pub fn mode_changes(&self, modes: &str, params: &[String]) -> Vec<(bool, char, Option<String>)> {
+        let mut params = params.iter();
+        let mut adding = true;
+        let mut changes = Vec::new();
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ => {
+                    let takes_param = self.is_prefix_mode(mode)
+                        || self.chanmodes[0].contains(mode)
+                        || self.chanmodes[1].contains(mode)
+                        || (adding && self.chanmodes[2].contains(mode));
+                    let param = if takes_param { params.next().cloned() } else { None };
+                    changes.push((adding, mode, param));
+                }
+            }
+        }
+        changes
+    }
+
+    // Most targets a command takes at once, None when there is no limit
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        self.targmax.get(command).copied().flatten()
+    }
+}
@@ -0,0 +1,264 @@
+// layout.rs
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use ratatui::layout::Rect;
+use serde::{Deserialize, Serialize};
+
+// Share of a split the smaller side keeps, in percent
+const MIN_RATIO: i32 = 10;
+const MAX_RATIO: i32 = 90;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitDir {
+    // Side by side
+    Vertical,
+    // One above the other
+    Horizontal,
+}
+
+// A pane shows one buffer, a split shares its area between two nodes with ratio percent for the first.
+// The same shape is read from [layouts.<name>] in the config
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Node {
+    Pane {
+        server: String,
+        channel: String,
+    },
+    Split {
+        split: SplitDir,
+        #[serde(default = "half")]
+        ratio: u16,
+        first: Box<Node>,
+        second: Box<Node>,
+    },
+}
+
+fn half() -> u16 { 50 }
+
+impl Node {
+    pub fn pane(server: &str, channel: &str) -> Node {
+        Node::Pane { server: server.to_string(), channel: channel.to_string() }
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            Node::Pane { .. } => 1,
+            Node::Split { first, second, .. } => first.count() + second.count(),
+        }
+    }
+
+    // Buffers of the panes, left to right and top to bottom
+    fn collect<'a>(&'a self, out: &mut Vec<(&'a str, &'a str)>) {
+        match self {
+            Node::Pane { server, channel } => out.push((server, channel)),
+            Node::Split { first, second, .. } => {
+                first.collect(out);
+                second.collect(out);
+            }
+        }
+    }
+
+    fn nth_mut(&mut self, index: usize) -> Option<&mut Node> {
+        match self {
+            Node::Pane { .. } => (index == 0).then_some(self),
+            Node::Split { first, second, .. } => {
+                let first_count = first.count();
+                if index < first_count { first.nth_mut(index) } else { second.nth_mut(index - first_count) }
+            }
+        }
+    }
+
+    // Every pane that matches shows another buffer instead
+    fn replace(&mut self, matches: &impl Fn(&str, &str) -> bool, server_id: &str, channel_id: &str) {
+        match self {
+            Node::Pane { server, channel } => {
+                if matches(server, channel) {
+                    *server = server_id.to_string();
+                    *channel = channel_id.to_string();
+                }
+            }
+            Node::Split { first, second, .. } => {
+                first.replace(matches, server_id, channel_id);
+                second.replace(matches, server_id, channel_id);
+            }
+        }
+    }
+
+    // Removes pane index, its split is replaced by the other side
+    fn close(&mut self, index: usize) -> bool {
+        let Node::Split { first, second, .. } = self else {
+            return false;
+        };
+        let first_count = first.count();
+        let keep = if index == 0 && first_count == 1 {
+            std::mem::replace(second.as_mut(), Node::pane("", ""))
+        } else if index == first_count && second.count() == 1 {
+            std::mem::replace(first.as_mut(), Node::pane("", ""))
+        } else if index < first_count {
+            return first.close(index);
+        } else {
+            return second.close(index - first_count);
+        };
+        *self = keep;
+        true
+    }
+
+    // The split right above pane index grows the side of that pane by delta percent
+    fn resize(&mut self, index: usize, delta: i32) -> bool {
+        let Node::Split { ratio, first, second, .. } = self else {
+            return false;
+        };
+        let first_count = first.count();
+        let (side, side_index, sign) = if index < first_count { (first, index, 1) } else { (second, index - first_count, -1) };
+        if side.count() == 1 {
+            *ratio = (*ratio as i32 + sign * delta).clamp(MIN_RATIO, MAX_RATIO) as u16;
+            return true;
+        }
+        side.resize(side_index, delta)
+    }
+
+    fn rects(&self, area: Rect, out: &mut Vec<Rect>) {
+        match self {
+            Node::Pane { .. } => out.push(area),
+            Node::Split { split, ratio, first, second } => {
+                let (first_area, second_area) = match split {
+                    SplitDir::Vertical => {
+                        let width = (area.width as u32 * *ratio as u32 / 100) as u16;
+                        (Rect { width, ..area }, Rect { x: area.x + width, width: area.width - width, ..area })
+                    }
+                    SplitDir::Horizontal => {
+                        let height = (area.height as u32 * *ratio as u32 / 100) as u16;
+                        (Rect { height, ..area }, Rect { y: area.y + height, height: area.height - height, ..area })
+                    }
+                };
+                first.rects(first_area, out);
+                second.rects(second_area, out);
+            }
+        }
+    }
+}
+
+// The panes of the chat area, the focused one shows the active buffer
+#[derive(Debug)]
+pub struct PaneLayout {
+    pub root: Node,
+    pub focus: usize,
+}
+
+impl Default for PaneLayout {
+    fn default() -> Self {
+        PaneLayout { root: Node::pane("System", "Status"), focus: 0 }
+    }
+}
+
+impl PaneLayout {
+    pub fn set(&mut self, root: Node) {
+        self.root = root;
+        self.focus = 0;
+    }
+
+    pub fn is_split(&self) -> bool {
+        matches!(self.root, Node::Split { .. })
+    }
+
+    pub fn panes(&self) -> Vec<(&str, &str)> {
+        let mut panes = Vec::new();
+        self.root.collect(&mut panes);
+        panes
+    }
+
+    pub fn focused(&self) -> (String, String) {
+        let panes = self.panes();
+        let (server, channel) = panes.get(self.focus).or(panes.first()).copied().unwrap_or(("System", "Status"));
+        (server.to_string(), channel.to_string())
+    }
+
+    // The focused pane switches to another buffer
+    pub fn show(&mut self, server_id: &str, channel_id: &str) {
+        if let Some(Node::Pane { server, channel }) = self.root.nth_mut(self.focus) {
+            if server != server_id || channel != channel_id {
+                *server = server_id.to_string();
+                *channel = channel_id.to_string();
+            }
+        }
+    }
+
+    // The focused pane is split in two, the new half shows the buffer and gets the focus
+    pub fn split(&mut self, dir: SplitDir, server_id: &str, channel_id: &str) {
+        if let Some(node) = self.root.nth_mut(self.focus) {
+            let old = std::mem::replace(node, Node::pane("", ""));
+            *node = Node::Split { split: dir, ratio: half(), first: Box::new(old), second: Box::new(Node::pane(server_id, channel_id)) };
+            self.focus += 1;
+        }
+    }
+
+    // The last pane can not be closed
+    pub fn close(&mut self) -> bool {
+        if !self.root.close(self.focus) {
+            return false;
+        }
+        self.focus = self.focus.min(self.root.count() - 1);
+        true
+    }
+
+    pub fn resize(&mut self, delta: i32) -> bool {
+        self.root.resize(self.focus, delta)
+    }
+
+    pub fn focus(&mut self, index: usize) -> bool {
+        if index >= self.root.count() {
+            return false;
+        }
+        self.focus = index;
+        true
+    }
+
+    pub fn cycle(&mut self) {
+        self.focus = (self.focus + 1) % self.root.count();
+    }
+
+    pub fn replace(&mut self, matches: impl Fn(&str, &str) -> bool, server_id: &str, channel_id: &str) {
+        self.root.replace(&matches, server_id, channel_id);
+    }
+
+    // Area of every pane, in the order of panes()
+    pub fn rects(&self, area: Rect) -> Vec<Rect> {
+        let mut rects = Vec::new();
+        self.root.rects(area, &mut rects);
+        rects
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LayoutFile {
+    #[serde(default)]
+    layouts: BTreeMap<String, Node>,
+}
+
+// Layouts saved with /layout save, kept apart from config.toml so the config is never rewritten
+fn saved_path() -> PathBuf {
+    let mut path = dirs_next::home_dir().unwrap_or_default();
+    path.push(".config/rustychat/layouts.toml");
+    path
+}
+
+pub fn read_saved() -> BTreeMap<String, Node> {
+    fs::read_to_string(saved_path()).ok()
+        .and_then(|content| toml::from_str::<LayoutFile>(&content).ok())
+        .map(|file| file.layouts)
+        .unwrap_or_default()
+}
+
+pub fn save(name: &str, node: &Node) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = LayoutFile { layouts: read_saved() };
+    file.layouts.insert(name.to_string(), node.clone());
+    let path = saved_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, toml::to_string(&file)?)?;
+    Ok(())
+}
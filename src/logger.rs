@@ -0,0 +1,151 @@
+// logger.rs
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use crate::casemap::CaseMapping;
+use crate::chatline::{ChatLine, LineKind};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// [logging.servers."irc.libera.chat"], unset entries fall back to the level above
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ServerLog {
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub channels: HashMap<String, bool>,
+}
+
+struct LogEntry {
+    dir: PathBuf,
+    file: PathBuf,
+    line: String,
+}
+
+// Appends chat lines to ~/.local/share/rustychat/logs/<network>/<channel>/YYYY-MM-DD.log,
+// the files are written on a blocking thread so the UI never waits for the disk
+#[derive(Default)]
+pub struct Logger {
+    pub enabled: bool,
+    pub format: LogFormat,
+    pub servers: HashMap<String, ServerLog>,
+    tx: Option<mpsc::UnboundedSender<LogEntry>>,
+}
+
+impl Logger {
+    pub fn start(&mut self) {
+        if self.tx.is_some() {
+            return;
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || write_loop(rx));
+        self.tx = Some(tx);
+    }
+
+    // A channel entry beats its server entry, which beats the global switch
+    pub fn is_enabled(&self, server_id: &str, channel: &str, mapping: CaseMapping) -> bool {
+        let Some(server) = self.servers.get(server_id) else {
+            return self.enabled;
+        };
+        server.channels.iter()
+            .find(|(name, _)| mapping.equal(name, channel))
+            .map(|(_, enabled)| *enabled)
+            .or(server.enabled)
+            .unwrap_or(self.enabled)
+    }
+
+    pub fn log(&self, network: &str, server_id: &str, channel: &str, mapping: CaseMapping, line: &ChatLine) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if !self.is_enabled(server_id, channel, mapping) {
+            return;
+        }
+        let dir = channel_path("logs", network, &mapping.fold(channel));
+        let file = dir.join(line.timestamp.format("%Y-%m-%d.log").to_string());
+        let _ = tx.send(LogEntry { dir, file, line: self.format_line(line) });
+    }
+
+    fn format_line(&self, line: &ChatLine) -> String {
+        match self.format {
+            LogFormat::Text => text_line(line),
+            LogFormat::Json => serde_json::json!({
+                "time": line.timestamp.to_rfc3339(),
+                "kind": kind_name(line.kind),
+                "sender": line.sender,
+                "text": line.text,
+                "msgid": line.msgid(),
+                "account": line.account(),
+            }).to_string(),
+        }
+    }
+}
+
+// ~/.local/share/rustychat/<store>/<server>/<channel>, channel folded so #Rust and #rust share it,
+// path separators in the names can not escape the store
+pub fn channel_path(store: &str, server: &str, channel: &str) -> PathBuf {
+    let clean = |name: &str| -> String {
+        let name: String = name.chars().map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c }).collect();
+        if name.starts_with('.') || name.is_empty() { format!("_{}", name) } else { name }
+    };
+    let mut path = dirs_next::home_dir().unwrap_or_default();
+    path.push(".local/share/rustychat");
+    path.join(store).join(clean(server)).join(clean(channel))
+}
+
+pub fn kind_name(kind: LineKind) -> &'static str {
+    match kind {
+        LineKind::Message => "message",
+        LineKind::Action => "action",
+        LineKind::Notice => "notice",
+        LineKind::Join => "join",
+        LineKind::Part => "part",
+        LineKind::Quit => "quit",
+        LineKind::Kick => "kick",
+        LineKind::Nick => "nick",
+        LineKind::Mode => "mode",
+        LineKind::Topic => "topic",
+        LineKind::Error => "error",
+        LineKind::System => "system",
+        LineKind::Marker => "marker",
+    }
+}
+
+// [12:30:05] <nick> text, * nick action, -nick- notice, -!- for everything else
+fn text_line(line: &ChatLine) -> String {
+    let time = line.timestamp.format("%H:%M:%S");
+    match line.kind {
+        LineKind::Message => format!("[{}] <{}> {}", time, line.sender, line.text),
+        LineKind::Action => format!("[{}] * {} {}", time, line.sender, line.text),
+        LineKind::Notice => format!("[{}] {} {}", time, line.sender, line.text),
+        _ => format!("[{}] -!- {} {}", time, line.sender, line.text),
+    }
+}
+
+fn write_loop(mut rx: mpsc::UnboundedReceiver<LogEntry>) {
+    //One open file per channel directory, a new date in the name starts the next day's file
+    let mut open: HashMap<PathBuf, (PathBuf, File)> = HashMap::new();
+    while let Some(entry) = rx.blocking_recv() {
+        if open.get(&entry.dir).is_none_or(|(path, _)| *path != entry.file) {
+            let file = fs::create_dir_all(&entry.dir)
+                .and_then(|_| OpenOptions::new().create(true).append(true).open(&entry.file));
+            match file {
+                Ok(file) => {
+                    open.insert(entry.dir.clone(), (entry.file.clone(), file));
+                }
+                Err(_) => continue,
+            }
+        }
+        if let Some((_, file)) = open.get_mut(&entry.dir) {
+            let _ = writeln!(file, "{}", entry.line.replace(['\r', '\n'], " "));
+        }
+    }
+}
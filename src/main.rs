@@ -5,6 +5,19 @@ mod event;
 mod config;
 mod textstyle;
 mod cursor;
+mod tls;
+mod sasl;
+mod caps;
+mod message;
+mod isupport;
+mod casemap;
+mod ctcp;
+mod chatline;
+mod logger;
+mod scrollback;
+mod search;
+mod highlight;
+mod layout;
 use crossterm::{
     execute, 
     terminal::{enable_raw_mode, disable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
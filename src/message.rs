@@ -0,0 +1,279 @@
+// message.rs
+use std::fmt;
+
+// nick!user@host, or just the server name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Prefix {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IrcMessage {
+    pub tags: Vec<(String, Option<String>)>,
+    pub source: Option<Prefix>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl Prefix {
+    pub fn parse(source: &str) -> Prefix {
+        let (rest, host) = match source.split_once('@') {
+            Some((rest, host)) => (rest, Some(host.to_string())),
+            None => (source, None),
+        };
+        let (nick, user) = match rest.split_once('!') {
+            Some((nick, user)) => (nick, Some(user.to_string())),
+            None => (rest, None),
+        };
+        Prefix { nick: nick.to_string(), user, host }
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.nick)?;
+        if let Some(user) = &self.user {
+            write!(f, "!{}", user)?;
+        }
+        if let Some(host) = &self.host {
+            write!(f, "@{}", host)?;
+        }
+        Ok(())
+    }
+}
+
+impl IrcMessage {
+    pub fn new(command: &str, params: Vec<String>) -> IrcMessage {
+        IrcMessage { command: command.to_string(), params, ..Default::default() }
+    }
+
+    // [@tags] [:source] <command> <params> [:trailing]
+    pub fn parse(line: &str) -> Option<IrcMessage> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut msg = IrcMessage::default();
+
+        if let Some(tagged) = rest.strip_prefix('@') {
+            let (tags, after) = tagged.split_once(' ').unwrap_or((tagged, ""));
+            for tag in tags.split(';').filter(|t| !t.is_empty()) {
+                let (key, value) = match tag.split_once('=') {
+                    Some((key, value)) if !value.is_empty() => (key, Some(unescape_tag_value(value))),
+                    Some((key, _)) => (key, None),
+                    None => (tag, None),
+                };
+                msg.tags.push((key.to_string(), value));
+            }
+            rest = after;
+        }
+        rest = rest.trim_start_matches(' ');
+
+        if let Some(sourced) = rest.strip_prefix(':') {
+            let (source, after) = sourced.split_once(' ').unwrap_or((sourced, ""));
+            msg.source = Some(Prefix::parse(source));
+            rest = after.trim_start_matches(' ');
+        }
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+        msg.command = command.to_uppercase();
+
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                msg.params.push(trailing.to_string());
+                break;
+            }
+            let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+            msg.params.push(param.to_string());
+            rest = after;
+        }
+        Some(msg)
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(key, _)| key == name).and_then(|(_, value)| value.as_deref())
+    }
+
+    pub fn nick(&self) -> &str {
+        self.source.as_ref().map_or("", |s| s.nick.as_str())
+    }
+
+    pub fn param(&self, index: usize) -> &str {
+        self.params.get(index).map_or("", |p| p.as_str())
+    }
+
+    // Last parameter, usually the message text
+    pub fn trailing(&self) -> &str {
+        self.params.last().map_or("", |p| p.as_str())
+    }
+
+    // Human readable line for the Status buffer, source and params without the protocol colons
+    pub fn status_text(&self) -> String {
+        let params = self.params.join(" ");
+        match &self.source {
+            Some(source) => format!("{} {}", source, params),
+            None => params,
+        }
+    }
+}
+
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.tags.is_empty() {
+            write!(f, "@")?;
+            for (i, (key, value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ";")?;
+                }
+                write!(f, "{}", key)?;
+                if let Some(value) = value {
+                    write!(f, "={}", escape_tag_value(value))?;
+                }
+            }
+            write!(f, " ")?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ":{} ", source)?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {}", param)?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
+            } else {
+                write!(f, " {}", last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        //A lone backslash at the end is dropped, unknown escapes keep the character
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn tag_strategy() -> impl Strategy<Value = (String, Option<String>)> {
+        //An empty value is sent as a bare key, so values are never Some("")
+        ("[+]?[a-z][a-z0-9/.-]{0,10}", proptest::option::of("[^\r\n\0]{1,20}"))
+    }
+
+    fn source_strategy() -> impl Strategy<Value = Prefix> {
+        ("[a-zA-Z\\[\\]{}|_^`-][a-zA-Z0-9\\[\\]{}|_^`-]{0,8}", proptest::option::of("~?[a-z]{1,8}"), proptest::option::of("[a-z0-9.:]{1,16}"))
+            .prop_map(|(nick, user, host)| Prefix { nick, user, host })
+    }
+
+    fn message_strategy() -> impl Strategy<Value = IrcMessage> {
+        (
+            proptest::collection::vec(tag_strategy(), 0..4),
+            proptest::option::of(source_strategy()),
+            "[A-Z]{3,8}|[0-9]{3}",
+            proptest::collection::vec("[^ :\r\n\0][^ \r\n\0]{0,10}", 0..4),
+            //Trailing with spaces, a leading ':' or empty
+            proptest::option::of(prop_oneof!["[^\r\n\0]{0,30}", ":[^\r\n\0]{0,10}", Just(String::new())]),
+        )
+            .prop_map(|(tags, source, command, mut params, trailing)| {
+                params.extend(trailing);
+                IrcMessage { tags, source, command, params }
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn parse_display_round_trip(msg in message_strategy()) {
+            let line = msg.to_string();
+            let parsed = IrcMessage::parse(&line).expect("serialized line parses");
+            prop_assert_eq!(&parsed, &msg);
+            prop_assert_eq!(IrcMessage::parse(&parsed.to_string()), Some(parsed));
+        }
+
+        #[test]
+        fn tag_value_round_trip(value in "[^\0]{0,30}") {
+            prop_assert_eq!(unescape_tag_value(&escape_tag_value(&value)), value);
+        }
+    }
+
+    #[test]
+    fn escapes_tag_specials() {
+        assert_eq!(escape_tag_value("a;b c\\d\re\nf"), "a\\:b\\sc\\\\d\\re\\nf");
+        assert_eq!(unescape_tag_value("a\\:b\\sc\\\\d\\re\\nf"), "a;b c\\d\re\nf");
+    }
+
+    #[test]
+    fn parses_tagged_line() {
+        let msg = IrcMessage::parse("@time=2024-01-01T00:00:00.000Z;msgid=a\\sb :nick!user@host PRIVMSG #rust :hello there\r\n").unwrap();
+        assert_eq!(msg.tag("time"), Some("2024-01-01T00:00:00.000Z"));
+        assert_eq!(msg.tag("msgid"), Some("a b"));
+        assert_eq!(msg.source, Some(Prefix { nick: "nick".into(), user: Some("user".into()), host: Some("host".into()) }));
+        assert_eq!(msg.command, "PRIVMSG");
+        assert_eq!(msg.params, vec!["#rust", "hello there"]);
+    }
+
+    #[test]
+    fn parses_line_without_prefix() {
+        let msg = IrcMessage::parse("PING :irc.example.net").unwrap();
+        assert_eq!(msg.source, None);
+        assert_eq!(msg.command, "PING");
+        assert_eq!(msg.params, vec!["irc.example.net"]);
+    }
+
+    #[test]
+    fn parses_numeric() {
+        let msg = IrcMessage::parse(":irc.example.net 005 me CHANTYPES=# NETWORK=Example :are supported by this server").unwrap();
+        assert_eq!(msg.nick(), "irc.example.net");
+        assert_eq!(msg.command, "005");
+        assert_eq!(msg.params, vec!["me", "CHANTYPES=#", "NETWORK=Example", "are supported by this server"]);
+    }
+
+    #[test]
+    fn parses_empty_tag_value() {
+        let msg = IrcMessage::parse("@k=;v :n PRIVMSG #c :x").unwrap();
+        assert_eq!(msg.tags, vec![("k".to_string(), None), ("v".to_string(), None)]);
+        assert_eq!(msg.tag("k"), None);
+        assert_eq!(msg.to_string(), "@k;v :n PRIVMSG #c x");
+    }
+}
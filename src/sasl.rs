@@ -0,0 +1,51 @@
+// sasl.rs
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Mechanism {
+    #[default]
+    Plain,
+    External,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SaslConfig {
+    pub mechanism: Mechanism,
+    pub account: String,
+    pub password: String,
+}
+
+impl SaslConfig {
+    pub fn from_config(mechanism: &str, account: &str, password: &str) -> Option<SaslConfig> {
+        let mechanism = match mechanism.to_uppercase().as_str() {
+            "PLAIN" => Mechanism::Plain,
+            "EXTERNAL" => Mechanism::External,
+            _ => return None,
+        };
+        Some(SaslConfig { mechanism, account: account.to_string(), password: password.to_string() })
+    }
+
+    pub fn mechanism_name(&self) -> &'static str {
+        match self.mechanism {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        }
+    }
+
+    //Lines to answer "AUTHENTICATE +" with, split in 400 byte chunks
+    pub fn responses(&self) -> Vec<String> {
+        let payload = match self.mechanism {
+            Mechanism::Plain => STANDARD.encode(format!("{}\0{}\0{}", self.account, self.account, self.password)),
+            Mechanism::External => String::new(),
+        };
+        if payload.is_empty() {
+            return vec!["AUTHENTICATE +".to_string()];
+        }
+
+        let mut lines: Vec<String> = payload.as_bytes().chunks(400).map(|c| "AUTHENTICATE ".to_string() + &String::from_utf8_lossy(c)).collect();
+        if payload.len() % 400 == 0 {
+            lines.push("AUTHENTICATE +".to_string());
+        }
+        lines
+    }
+}
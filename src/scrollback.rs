@@ -0,0 +1,150 @@
+// scrollback.rs
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use crate::casemap::CaseMapping;
+use crate::chatline::{ChatLine, LineKind};
+use crate::logger;
+
+pub const DEFAULT_SCROLLBACK_LINES: usize = 100;
+
+// One line in ~/.local/share/rustychat/scrollback/<server>/<channel>.jsonl
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredLine {
+    time: String,
+    kind: String,
+    sender: String,
+    text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    msgid: Option<String>,
+}
+
+impl StoredLine {
+    fn from_line(line: &ChatLine) -> StoredLine {
+        StoredLine {
+            time: line.timestamp.to_rfc3339(),
+            kind: logger::kind_name(line.kind).to_string(),
+            sender: line.sender.clone(),
+            text: line.text.clone(),
+            msgid: line.msgid().map(|id| id.to_string()),
+        }
+    }
+
+    fn into_line(self) -> Option<ChatLine> {
+        let kind = match self.kind.as_str() {
+            "message" => LineKind::Message,
+            "action" => LineKind::Action,
+            "notice" => LineKind::Notice,
+            "join" => LineKind::Join,
+            "part" => LineKind::Part,
+            "quit" => LineKind::Quit,
+            "kick" => LineKind::Kick,
+            "nick" => LineKind::Nick,
+            "mode" => LineKind::Mode,
+            "topic" => LineKind::Topic,
+            "error" => LineKind::Error,
+            _ => LineKind::System,
+        };
+        let mut line = ChatLine::new(kind, &self.sender, &self.text);
+        line.timestamp = DateTime::parse_from_rfc3339(&self.time).ok()?.with_timezone(&Local);
+        if let Some(id) = self.msgid {
+            line.tags.push(("msgid".to_string(), Some(id)));
+        }
+        Some(line)
+    }
+}
+
+pub(crate) struct StoreEntry {
+    pub(crate) file: PathBuf,
+    line: String,
+}
+
+// The last lines of every channel and query, kept so a buffer opened after a restart is not empty.
+// Appends go to a blocking thread, files are cut back to the newest lines once they hold twice as many
+#[derive(Default)]
+pub struct Scrollback {
+    pub lines: usize,
+    tx: Option<mpsc::UnboundedSender<StoreEntry>>,
+}
+
+impl Scrollback {
+    pub fn start(&mut self) {
+        if self.tx.is_some() || self.lines == 0 {
+            return;
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let keep = self.lines;
+        tokio::task::spawn_blocking(move || write_loop(rx, keep));
+        self.tx = Some(tx);
+    }
+
+    // Stored lines go to the test instead of the disk
+    #[cfg(test)]
+    pub(crate) fn capture(&mut self) -> mpsc::UnboundedReceiver<StoreEntry> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.tx = Some(tx);
+        rx
+    }
+
+    pub fn store(&self, server_id: &str, channel: &str, mapping: CaseMapping, line: &ChatLine) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if let Ok(json) = serde_json::to_string(&StoredLine::from_line(line)) {
+            let _ = tx.send(StoreEntry { file: store_file(server_id, &mapping.fold(channel)), line: json });
+        }
+    }
+
+    // Newest saved lines of a buffer, oldest first
+    pub fn restore(&self, server_id: &str, channel: &str, mapping: CaseMapping) -> Vec<ChatLine> {
+        if self.lines == 0 {
+            return Vec::new();
+        }
+        let Ok(content) = fs::read_to_string(store_file(server_id, &mapping.fold(channel))) else {
+            return Vec::new();
+        };
+        let stored: Vec<&str> = content.lines().collect();
+        stored[stored.len().saturating_sub(self.lines)..].iter()
+            .filter_map(|l| serde_json::from_str::<StoredLine>(l).ok())
+            .filter_map(StoredLine::into_line)
+            .collect()
+    }
+}
+
+fn store_file(server_id: &str, channel: &str) -> PathBuf {
+    let mut path = logger::channel_path("scrollback", server_id, channel).into_os_string();
+    path.push(".jsonl");
+    PathBuf::from(path)
+}
+
+fn write_loop(mut rx: mpsc::UnboundedReceiver<StoreEntry>, keep: usize) {
+    //Lines per file, counted on the first write to it
+    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+    while let Some(entry) = rx.blocking_recv() {
+        let count = counts.entry(entry.file.clone()).or_insert_with(|| {
+            fs::read_to_string(&entry.file).map_or(0, |c| c.lines().count())
+        });
+        if let Some(dir) = entry.file.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let written = OpenOptions::new().create(true).append(true).open(&entry.file)
+            .and_then(|mut file| writeln!(file, "{}", entry.line));
+        if written.is_err() {
+            continue;
+        }
+        *count += 1;
+        if *count >= keep * 2 {
+            if let Ok(content) = fs::read_to_string(&entry.file) {
+                let lines: Vec<&str> = content.lines().collect();
+                let kept = &lines[lines.len().saturating_sub(keep)..];
+                if fs::write(&entry.file, kept.join("\n") + "\n").is_ok() {
+                    *count = kept.len();
+                }
+            }
+        }
+    }
+}
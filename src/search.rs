@@ -0,0 +1,144 @@
+// search.rs
+use chrono::{DateTime, Local};
+use regex::{Regex, RegexBuilder};
+use crate::app::{App, Popup};
+use crate::chatline::ChatLine;
+use crate::{cursor, textstyle};
+
+// A line matching the search, found again by time and text since the buffer keeps changing
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub server: String,
+    pub channel: String,
+    pub time: DateTime<Local>,
+    pub sender: String,
+    pub text: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Search {
+    // Matches are highlighted in the chat while this is set
+    pub regex: Option<Regex>,
+    // Results of /search for the popup, newest first
+    pub hits: Vec<SearchHit>,
+    // Ctrl-F mode, the prompt holds the pattern and the typed line waits in saved_prompt
+    pub typing: bool,
+    pub saved_prompt: String,
+    // Line of the active buffer the last jump went to
+    pub current: Option<usize>,
+}
+
+// Case insensitive unless the pattern says otherwise with (?-i)
+pub fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+// /search <regex>, every matching line of every buffer in the popup
+pub fn search_all(app: &mut App, pattern: &str) -> Result<usize, regex::Error> {
+    let regex = compile(pattern)?;
+    let mut hits = Vec::new();
+    for (server_id, server) in &app.server_list {
+        for (name, channel) in &server.channels {
+            for line in channel.chat_list.iter().filter(|l| regex.is_match(&l.text)) {
+                hits.push(SearchHit {
+                    server: server_id.clone(),
+                    channel: name.to_string(),
+                    time: line.timestamp,
+                    sender: line.sender.clone(),
+                    text: line.text.clone(),
+                });
+            }
+        }
+    }
+    hits.sort_by_key(|h| std::cmp::Reverse(h.time));
+    let count = hits.len();
+    app.search.hits = hits;
+    app.search.regex = Some(regex);
+    app.search_state.select(if count > 0 { Some(0) } else { None });
+    app.popup = Popup::Search;
+    Ok(count)
+}
+
+// Switch to the buffer of the selected popup result and scroll to it
+pub fn open_selected(app: &mut App) {
+    let Some(hit) = app.search_state.selected().and_then(|i| app.search.hits.get(i)).cloned() else {
+        return;
+    };
+    let index = app.server_list.get(&hit.server)
+        .and_then(|s| s.channel(&hit.channel))
+        .and_then(|c| c.chat_list.iter().position(|l| l.timestamp == hit.time && l.text == hit.text));
+    let Some(index) = index else {
+        return;
+    };
+    app.active_server = hit.server.clone();
+    app.active_channel = hit.channel.clone();
+    if let Some(server) = app.server_list.get(&hit.server) {
+        app.active_nick = server.nick.clone();
+    }
+    scroll_to(app, index);
+    app.popup = Popup::None;
+}
+
+// Ctrl-F, the prompt becomes the search pattern until Esc
+pub fn start_typing(app: &mut App) {
+    if app.search.typing {
+        step(app, true);
+        return;
+    }
+    app.search.typing = true;
+    app.search.current = None;
+    app.search.saved_prompt = std::mem::take(&mut app.prompt);
+    cursor::reset_cursor(app);
+}
+
+// The pattern was edited, jump to the newest match
+pub fn pattern_changed(app: &mut App) {
+    app.search.regex = compile(&app.prompt).ok().filter(|_| !app.prompt.is_empty());
+    app.search.current = None;
+    step(app, true);
+}
+
+// Next match above (older) or below (newer) the current one in the active buffer
+pub fn step(app: &mut App, older: bool) {
+    let Some(regex) = &app.search.regex else {
+        return;
+    };
+    let Some(lines) = app.server_list.get(&app.active_server).and_then(|s| s.channel(&app.active_channel)).map(|c| &c.chat_list) else {
+        return;
+    };
+    if let Some(index) = next_match(lines, regex, app.search.current, older) {
+        app.search.current = Some(index);
+        scroll_to(app, index);
+    }
+}
+
+// Leave Ctrl-F mode, the typed line comes back and the highlight goes away
+pub fn stop(app: &mut App) {
+    if app.search.typing {
+        app.prompt = std::mem::take(&mut app.search.saved_prompt);
+        let map = cursor::build_prompt_cursor_map(&app.prompt);
+        app.character_index = map.visible_to_raw.len();
+    }
+    app.search = Search::default();
+    if app.popup == Popup::Search {
+        app.popup = Popup::None;
+    }
+}
+
+fn next_match(lines: &[ChatLine], regex: &Regex, from: Option<usize>, older: bool) -> Option<usize> {
+    let is_match = |i: &usize| regex.is_match(&lines[*i].text);
+    match (from, older) {
+        (None, _) => (0..lines.len()).rev().find(is_match),
+        (Some(from), true) => (0..from.min(lines.len())).rev().find(is_match),
+        (Some(from), false) => (from + 1..lines.len()).find(is_match),
+    }
+}
+
+fn scroll_to(app: &mut App, index: usize) {
+    let (server_id, channel_id) = (app.active_server.clone(), app.active_channel.clone());
+    let rows = textstyle::rows_after(app, &server_id, &channel_id, index);
+    if let Some(channel) = app.server_list.get_mut(&server_id).and_then(|s| s.channel_mut(&channel_id)) {
+        channel.chat_pos = rows;
+        channel.mark_read();
+    }
+}
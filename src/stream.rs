@@ -1,7 +1,19 @@
 // stream.rs
-use tokio::{net::TcpStream, io::{AsyncBufReadExt, AsyncWriteExt, BufReader}};
+use tokio::{net::TcpStream, io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader}};
 use tokio::sync::mpsc;
-use std::collections::HashMap;
+use tokio::time::Instant as TokioInstant;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::tls;
+use crate::message::IrcMessage;
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(90);
+pub const FLOOD_BURST: u32 = 5;
+pub const FLOOD_RATE: f64 = 0.5;
+// Twitch allows 20 messages per 30 seconds
+pub const TWITCH_FLOOD_BURST: u32 = 10;
+pub const TWITCH_FLOOD_RATE: f64 = 0.33;
 
 
 pub struct ConnectionHandle {
@@ -10,15 +22,46 @@ pub struct ConnectionHandle {
 
 pub enum ConnCommand {
     SendLine(String),
+    Reconnect,
     Quit,
 }
 
 pub type ServerId = String;
 
+// Plain tcp or tls, the connection task only needs to read and write lines
+trait IrcStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IrcStream for T {}
+
+#[derive(Clone, Default)]
+pub struct ConnectInfo {
+    pub addr: String,
+    pub nick: String,
+    pub real: String,
+    pub oauth: String,
+    pub tls: bool,
+    pub tls_ca: Option<String>,
+    pub client_cert: Option<String>,
+    pub auto_reconnect: bool,
+    // Token bucket for outgoing lines, burst lines at once then rate lines per second
+    pub flood_burst: u32,
+    pub flood_rate: f64,
+}
+
 #[derive(Debug)]
 pub enum NetEvent {
     Line(String),
     Error(String),
+    Connected,
+    Disconnected(String),
+    Lag(Duration),
+    Queue(usize),
+}
+
+// How a connected session ended
+enum SessionEnd {
+    Lost(String),
+    Reconnect,
+    Quit,
 }
 
 #[derive(Default)]
@@ -27,58 +70,56 @@ pub struct StreamManager {
 }
 
 impl StreamManager {
-    pub async fn connect(&mut self, server_id: ServerId, addr: String, net_tx: mpsc::UnboundedSender<(ServerId, NetEvent)>, nick: String, real: String, oauth: String) -> bool{
+    pub async fn connect(&mut self, server_id: ServerId, info: ConnectInfo, net_tx: mpsc::UnboundedSender<(ServerId, NetEvent)>) -> bool{
         let (tx, mut rx) = mpsc::unbounded_channel();
         let net_tx2 = net_tx.clone();
         let sid = server_id.clone();
         tokio::spawn(async move {
-            let stream = match TcpStream::connect(&addr).await {
-                Ok(s) => s,
-                Err(e) => {
-                    let _ = net_tx2.send((sid.clone(), NetEvent::Error(format!("Failed to connect: {e}"))));
-                    return;
-                }
-            };
-            let (r, mut w) = stream.into_split();
-            let mut reader = BufReader::new(r).lines();
-
-            let w_oauth = "PASS oauth:".to_owned() + &oauth + "\r\n";
-            let cap_user = "CAP REQ :twitch.tv/membership".to_owned() + "\r\n";
-            let w_nick = "NICK ".to_owned() + &nick + "\r\n";
-            let w_real = "USER guest 0 * :".to_owned() + &real + "\r\n";
-
-            if oauth.is_empty() {
-                let _ = w.write_all(w_nick.as_bytes()).await;
-                let _ = w.write_all(w_real.as_bytes()).await;
-            } else {
-                let _ = w.write_all(w_oauth.as_bytes()).await;
-                let _ = w.write_all(w_nick.as_bytes()).await;
-                let _ = w.write_all(cap_user.as_bytes()).await;
-            }
-
+            let mut attempt: u32 = 0;
             loop {
-                tokio::select! {
-                    Some(cmd) = rx.recv() => match cmd {
-                        ConnCommand::SendLine(s) => {
-                            let _ = w.write_all(s.as_bytes()).await;
-                            let _ = w.write_all(b"\r\n").await; 
-                        }
-                        ConnCommand::Quit => break,
-                    },
-                    result = reader.next_line() => {
-                        match result {
-                            Ok(Some(line)) => {
-                                let _ = net_tx2.send((sid.clone(), NetEvent::Line(line)));
-                            }
-                            Ok(None) => {
-                                let _ = net_tx2.send((sid.clone(), NetEvent::Error("Disconnected".to_string())));
-                                break;
+                let reason = match open_stream(&info).await {
+                    Ok(stream) => {
+                        attempt = 0;
+                        let _ = net_tx2.send((sid.clone(), NetEvent::Connected));
+                        match run_session(stream, &info, &mut rx, &net_tx2, &sid).await {
+                            SessionEnd::Quit => return,
+                            SessionEnd::Reconnect => {
+                                let _ = net_tx2.send((sid.clone(), NetEvent::Disconnected("Reconnecting".to_string())));
+                                continue;
                             }
-                            Err(e) => {
-                                let _ = net_tx2.send((sid.clone(), NetEvent::Error(format!("Read error: {e}"))));
+                            SessionEnd::Lost(reason) => reason,
+                        }
+                    }
+                    Err(e) => {
+                        let _ = net_tx2.send((sid.clone(), NetEvent::Error(format!("Failed to connect: {e}"))));
+                        "Connection failed".to_string()
+                    }
+                };
+
+                //Wait for the backoff, or for /reconnect when auto reconnect is off
+                let delay = if info.auto_reconnect {
+                    let delay = backoff(attempt);
+                    attempt = attempt.saturating_add(1);
+                    let _ = net_tx2.send((sid.clone(), NetEvent::Disconnected(format!("{reason}, reconnecting in {} seconds", delay.as_secs()))));
+                    Some(delay)
+                } else {
+                    let _ = net_tx2.send((sid.clone(), NetEvent::Disconnected(format!("{reason}, use /reconnect"))));
+                    None
+                };
+                let sleep = tokio::time::sleep(delay.unwrap_or_default());
+                tokio::pin!(sleep);
+                loop {
+                    tokio::select! {
+                        _ = &mut sleep, if delay.is_some() => break,
+                        cmd = rx.recv() => match cmd {
+                            Some(ConnCommand::Reconnect) => {
+                                attempt = 0;
                                 break;
                             }
-                        }
+                            Some(ConnCommand::Quit) | None => return,
+                            //Nothing to send to while disconnected
+                            Some(ConnCommand::SendLine(_)) => {}
+                        },
                     }
                 }
             }
@@ -93,6 +134,12 @@ impl StreamManager {
             let _ = conn.tx.send(ConnCommand::SendLine(line));
         }
     }
+    pub fn reconnect(&self, server_id: &str) -> bool {
+        match self.conns.get(server_id) {
+            Some(conn) => conn.tx.send(ConnCommand::Reconnect).is_ok(),
+            None => false,
+        }
+    }
     pub fn disconnect(&mut self, server_id: &str) {
         if let Some(conn) = self.conns.remove(server_id) {
             // Send the Quit command to the task
@@ -108,3 +155,147 @@ impl StreamManager {
 
 }
 
+async fn open_stream(info: &ConnectInfo) -> std::io::Result<Box<dyn IrcStream>> {
+    let tcp = TcpStream::connect(&info.addr).await?;
+    if info.tls {
+        let host = info.addr.rsplit_once(':').map_or(info.addr.as_str(), |(h, _)| h);
+        let stream = tls::connect(tcp, host, info.tls_ca.as_deref(), info.client_cert.as_deref()).await?;
+        Ok(Box::new(stream))
+    } else {
+        Ok(Box::new(tcp))
+    }
+}
+
+async fn run_session(stream: Box<dyn IrcStream>, info: &ConnectInfo, rx: &mut mpsc::UnboundedReceiver<ConnCommand>, net_tx: &mpsc::UnboundedSender<(ServerId, NetEvent)>, sid: &ServerId) -> SessionEnd {
+    let (r, mut w) = tokio::io::split(stream);
+    let mut reader = BufReader::new(r).lines();
+
+    let w_oauth = "PASS oauth:".to_owned() + &info.oauth + "\r\n";
+    let w_nick = "NICK ".to_owned() + &info.nick + "\r\n";
+    let w_real = "USER guest 0 * :".to_owned() + &info.real + "\r\n";
+
+    //Registration is held open until App sends CAP END
+    let _ = w.write_all(b"CAP LS 302\r\n").await;
+    if info.oauth.is_empty() {
+        let _ = w.write_all(w_nick.as_bytes()).await;
+        let _ = w.write_all(w_real.as_bytes()).await;
+    } else {
+        let _ = w.write_all(w_oauth.as_bytes()).await;
+        let _ = w.write_all(w_nick.as_bytes()).await;
+    }
+
+    //Our own PING, sent after PING_INTERVAL without a line to measure lag, no PONG within PING_TIMEOUT drops the link
+    let mut ping_check = tokio::time::interval(Duration::from_secs(5));
+    let mut last_ping = Instant::now();
+    let mut ping_sent: Option<(Instant, String)> = None;
+
+    let mut queue = SendQueue::new(info.flood_burst, info.flood_rate);
+    let mut reported_depth = 0;
+
+    loop {
+        //Write what the token bucket allows, and let App know how much is waiting
+        while let Some(line) = queue.pop() {
+            let _ = w.write_all(line.as_bytes()).await;
+            let _ = w.write_all(b"\r\n").await;
+        }
+        if queue.len() != reported_depth {
+            reported_depth = queue.len();
+            let _ = net_tx.send((sid.clone(), NetEvent::Queue(reported_depth)));
+        }
+
+        tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(ConnCommand::SendLine(s)) => queue.push(s),
+                Some(ConnCommand::Reconnect) => return SessionEnd::Reconnect,
+                Some(ConnCommand::Quit) | None => return SessionEnd::Quit,
+            },
+            _ = tokio::time::sleep_until(queue.next_token()), if queue.len() > 0 => {}
+            _ = ping_check.tick() => {
+                match &ping_sent {
+                    Some((sent, _)) if sent.elapsed() > PING_TIMEOUT => {
+                        return SessionEnd::Lost(format!("Ping timeout: {} seconds", PING_TIMEOUT.as_secs()));
+                    }
+                    Some(_) => {}
+                    None if last_ping.elapsed() > PING_INTERVAL => {
+                        let token = format!("rustychat-{}", SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis()));
+                        let _ = w.write_all(format!("PING :{}\r\n", token).as_bytes()).await;
+                        ping_sent = Some((Instant::now(), token));
+                        last_ping = Instant::now();
+                    }
+                    None => {}
+                }
+            }
+            result = reader.next_line() => {
+                match result {
+                    Ok(Some(line)) => {
+                        last_ping = Instant::now();
+                        //Answer to our own PING is used for lag and not passed on
+                        if let Some((sent, token)) = &ping_sent {
+                            if IrcMessage::parse(&line).is_some_and(|m| m.command == "PONG" && m.trailing() == token) {
+                                let _ = net_tx.send((sid.clone(), NetEvent::Lag(sent.elapsed())));
+                                ping_sent = None;
+                                continue;
+                            }
+                        }
+                        let _ = net_tx.send((sid.clone(), NetEvent::Line(line)));
+                    }
+                    Ok(None) => return SessionEnd::Lost("Disconnected".to_string()),
+                    Err(e) => return SessionEnd::Lost(format!("Read error: {e}")),
+                }
+            }
+        }
+    }
+}
+
+// Exponential backoff from 2 seconds up to 5 minutes, with +-25% jitter
+fn backoff(attempt: u32) -> Duration {
+    let base = 2u64.saturating_mul(1 << attempt.min(8)).min(300) * 1000;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos() as u64);
+    let jitter = nanos % (base / 2 + 1);
+    Duration::from_millis(base - base / 4 + jitter)
+}
+
+// Outgoing lines waiting for the token bucket, keeps us from being killed for Excess Flood
+struct SendQueue {
+    lines: VecDeque<String>,
+    tokens: f64,
+    burst: f64,
+    rate: f64,
+    last: Instant,
+}
+
+impl SendQueue {
+    fn new(burst: u32, rate: f64) -> SendQueue {
+        let burst = burst.max(1) as f64;
+        SendQueue { lines: VecDeque::new(), tokens: burst, burst, rate: rate.max(0.01), last: Instant::now() }
+    }
+
+    fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    fn push(&mut self, line: String) {
+        //PONG goes first so a long queue does not get us ping timed out
+        if line.starts_with("PONG") {
+            self.lines.push_front(line);
+        } else {
+            self.lines.push_back(line);
+        }
+    }
+
+    fn pop(&mut self) -> Option<String> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 && !self.lines.is_empty() {
+            self.tokens -= 1.0;
+            return self.lines.pop_front();
+        }
+        None
+    }
+
+    fn next_token(&self) -> TokioInstant {
+        let wait = ((1.0 - self.tokens) / self.rate).max(0.0);
+        TokioInstant::from_std(self.last) + Duration::from_secs_f64(wait)
+    }
+}
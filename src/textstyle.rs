@@ -1,5 +1,5 @@
 use ratatui::text::Span;
-use unicode_width::UnicodeWidthChar;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use ratatui::style::Style;
 use ratatui::style::Color;
 use ratatui::style::Modifier;
@@ -8,6 +8,10 @@ use ratatui::text::Line;
 use textwrap::{wrap, Options};
 use std::borrow::Cow;
 use crate::cursor;
+use crate::app::ChannelData;
+use crate::chatline::{ChatLine, LineKind};
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
 
 
 #[derive(Clone)]
@@ -141,8 +145,7 @@ fn text_style<'a, F>(line: &Cow<str>, mut spans: Vec<Span<'a>>, mut current_styl
                 }
             }
             '\u{1}' => {
-                //u{1} CTCP VERSION Request send NOTICE response
-                //Consume the char
+                //CTCP delimiter, ACTION is drawn by chat_style and queries never reach the chat
             }
             '\u{F}' => {
                 if !text.is_empty() {
@@ -171,61 +174,134 @@ pub fn chat_style(app: &App, server_id: String, channel_id: String) -> Vec<Line>
 
     let mut chat_lines: Vec<Line> = Vec::new();
 
+    let pane_width = app.pane_rect(&server_id, &channel_id).width as usize;
     if let Some(server) = app.server_list.get(&server_id) {
-        if let Some(channel) = server.channels.get(&channel_id) {
+        if let Some(channel) = server.channel(&channel_id) {
+            for chat_line in channel.chat_list.iter() {
+                chat_lines.extend(chat_line_rows(app, channel, chat_line, pane_width));
+            }
+        }
+    }
 
-            for (sender, line) in channel.chat_list.clone() {
-                //let (sender, line) = lines;
+    return chat_lines;
+}
 
+// Wrapped rows drawn below a line, as chat_pos that puts the line at the bottom of the chat
+pub fn rows_after(app: &App, server_id: &str, channel_id: &str, index: usize) -> usize {
+    rows_from(app, server_id, channel_id, index + 1)
+}
 
-                let nick_width = 10;
+// Wrapped rows of the whole buffer, the same count chat_style draws
+pub fn total_rows(app: &App, server_id: &str, channel_id: &str) -> usize {
+    rows_from(app, server_id, channel_id, 0)
+}
 
-                // Truncate and pad nicknames
-                let trimmed_nick = if sender.chars().count() > nick_width {
-                    // Truncate to max_nick_width - 1 and add '…'
-                    format!("{:.1$}…", sender, nick_width - 1)
-                } else {
-                    format!("{:<width$}", sender, width = nick_width)
-                };
-                let prefix = format!("{}: ", trimmed_nick);
+fn rows_from(app: &App, server_id: &str, channel_id: &str, index: usize) -> usize {
+    let Some(channel) = app.server_list.get(server_id).and_then(|s| s.channel(channel_id)) else {
+        return 0;
+    };
+    let pane_width = app.pane_rect(server_id, channel_id).width as usize;
+    channel.chat_list.iter().skip(index).map(|l| chat_line_rows(app, channel, l, pane_width).len()).sum()
+}
 
-                let wrap_width;
-                //chat window horizontal "linewrap"
-                let (on, _, _, _, _) = app.split;
-                if on == true {
-                    wrap_width = (app.w as usize / 2) - 6 - prefix.len();
-                } else {
-                    wrap_width = app.w as usize-4 - prefix.len();
-                }
+fn chat_line_rows(app: &App, channel: &ChannelData, chat_line: &ChatLine, pane_width: usize) -> Vec<Line<'static>> {
+    let mut chat_lines: Vec<Line> = Vec::new();
+    let timestamp = format!("{} ", chat_line.timestamp.format(&app.timestamp_format));
 
-                let (tr, tg, tb) = app.style_txt;
-                let current_style = Style::new().fg(Color::Rgb(tr, tg, tb));
+    //ACTION is drawn as "* nick does something"
+    let (sender, line, separator) = match chat_line.kind {
+        LineKind::Action => ("*".to_string(), format!("{} {}", chat_line.sender, chat_line.text), "  "),
+        _ => (chat_line.sender.clone(), chat_line.text.clone(), ": "),
+    };
+    //+draft/reply names the msgid of the line being answered
+    let replied = chat_line.reply_to()
+        .and_then(|id| channel.chat_list.iter().find(|l| l.msgid() == Some(id)));
+    let line = match replied {
+        Some(original) => format!("\u{1D}(re {})\u{1D} {}", original.sender, line),
+        None => line,
+    };
 
-                let data_wrap = line_wrap(wrap_width, &line);
 
-                for (i, line) in data_wrap.iter().enumerate() {
+    let nick_width = 10;
 
-                    let mut spans = Vec::new();
-                    // Add the prefix span to the start of this line EG Nick with spacing
-                    if i == 0 {
-                        let (hr, hg, hb) = app.style_highlight;
-                        spans.push(Span::styled(
-                            prefix.clone(),
-                            Style::default().fg(Color::Rgb(hr, hg, hb)).add_modifier(Modifier::BOLD),
-                        ));
-                    } else {
-                        // Add blank prefix of the same width for alignment
-                        spans.push(Span::raw(" ".repeat(prefix.len())));
-                    }
+    // Truncate and pad nicknames
+    let trimmed_nick = if sender.chars().count() > nick_width {
+        // Truncate to max_nick_width - 1 and add '…'
+        format!("{:.1$}…", sender, nick_width - 1)
+    } else {
+        format!("{:<width$}", sender, width = nick_width)
+    };
+    let prefix = format!("{}{}", trimmed_nick, separator);
+    let gutter = timestamp.width() + prefix.len();
+
+    //chat window horizontal "linewrap", inside the borders of the pane
+    let wrap_width = pane_width.saturating_sub(4 + gutter).max(1);
+
+    let (tr, tg, tb) = app.style_txt;
+    let timestamp_style = Style::new().fg(Color::Rgb(tr, tg, tb)).add_modifier(Modifier::DIM);
+    //Errors in red, the read marker in the notification colour, lines matching a highlight rule bold in the highlight colour, joins and parts dimmed
+    let current_style = if chat_line.kind == LineKind::Error {
+        Style::new().fg(Color::Red)
+    } else if chat_line.kind == LineKind::Marker {
+        let (nr, ng, nb) = app.style_notif;
+        Style::new().fg(Color::Rgb(nr, ng, nb))
+    } else if chat_line.highlight {
+        let (hr, hg, hb) = app.style_highlight;
+        Style::new().fg(Color::Rgb(hr, hg, hb)).add_modifier(Modifier::BOLD)
+    } else if chat_line.is_event() {
+        timestamp_style
+    } else {
+        Style::new().fg(Color::Rgb(tr, tg, tb))
+    };
 
-                    let spans = text_style(line, spans, current_style, app, |_,_| {});
-                    chat_lines.push(Line::from(spans));
-                }
-            }
+    let data_wrap = line_wrap(wrap_width, &line);
+
+    for (i, line) in data_wrap.iter().enumerate() {
+
+        let mut spans = Vec::new();
+        // Add the prefix span to the start of this line EG Nick with spacing
+        if i == 0 {
+            spans.push(Span::styled(timestamp.clone(), timestamp_style));
+            let (hr, hg, hb) = app.style_highlight;
+            spans.push(Span::styled(
+                prefix.clone(),
+                Style::default().fg(Color::Rgb(hr, hg, hb)).add_modifier(Modifier::BOLD),
+            ));
+        } else {
+            // Add blank prefix of the same width for alignment
+            spans.push(Span::raw(" ".repeat(gutter)));
         }
+
+        let prefix_spans = spans.len();
+        let mut spans = text_style(line, spans, current_style, app, |_,_| {});
+        if let Some(regex) = &app.search.regex {
+            let text_spans = spans.split_off(prefix_spans);
+            spans.extend(text_spans.into_iter().flat_map(|span| mark_matches(span, regex)));
+        }
+        chat_lines.push(Line::from(spans));
     }
+    chat_lines
+}
 
-    return chat_lines;
+// Search matches inside a span drawn reversed
+fn mark_matches(span: Span<'static>, regex: &Regex) -> Vec<Span<'static>> {
+    let text = span.content.to_string();
+    let mut pieces = Vec::new();
+    let mut last = 0;
+    for found in regex.find_iter(&text).filter(|m| !m.is_empty()) {
+        if found.start() > last {
+            pieces.push(Span::styled(text[last..found.start()].to_string(), span.style));
+        }
+        pieces.push(Span::styled(found.as_str().to_string(), span.style.add_modifier(Modifier::REVERSED)));
+        last = found.end();
+    }
+    if last == 0 {
+        return vec![span];
+    }
+    if last < text.len() {
+        pieces.push(Span::styled(text[last..].to_string(), span.style));
+    }
+    pieces
 }
 
 pub fn visible_prompt_and_cursor_offset<'a>(prompt: &'a str, max_width: usize, app: &mut App) -> (Vec<Span<'a>>, usize) {
@@ -316,3 +392,156 @@ pub fn visible_prompt_and_cursor_offset<'a>(prompt: &'a str, max_width: usize, a
     (visible_spans, cursor_offset)
 }
 
+
+// Formatting codes that are still open at some point in a message
+#[derive(Clone, Default)]
+struct FormatState {
+    toggles: Vec<char>,
+    fg: Option<String>,
+    bg: Option<String>,
+}
+
+impl FormatState {
+    fn apply(&mut self, code: &str) {
+        let mut chars = code.chars();
+        match chars.next() {
+            Some('\u{F}') => *self = FormatState::default(),
+            Some('\u{3}') => {
+                let rest = chars.as_str();
+                let (fg, bg) = match rest.split_once(',') {
+                    Some((fg, bg)) => (fg, Some(bg)),
+                    None => (rest, None),
+                };
+                if fg.is_empty() {
+                    self.fg = None;
+                    self.bg = None;
+                } else {
+                    self.fg = Some(format!("{:0>2}", fg));
+                    if let Some(bg) = bg {
+                        self.bg = Some(format!("{:0>2}", bg));
+                    }
+                }
+            }
+            Some(c) => {
+                if let Some(i) = self.toggles.iter().position(|t| *t == c) {
+                    self.toggles.remove(i);
+                } else {
+                    self.toggles.push(c);
+                }
+            }
+            None => {}
+        }
+    }
+
+    // Codes that reopen this state at the start of a continuation line
+    fn codes(&self) -> String {
+        let mut codes: String = self.toggles.iter().collect();
+        if let Some(fg) = &self.fg {
+            codes.push('\u{3}');
+            codes.push_str(fg);
+            if let Some(bg) = &self.bg {
+                codes.push(',');
+                codes.push_str(bg);
+            }
+        }
+        codes
+    }
+}
+
+// Formatting codes (colour with its digits) are kept whole, text is split at word boundaries
+fn format_tokens(text: &str) -> Vec<(bool, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if !matches!(c, '\u{2}' | '\u{3}' | '\u{F}' | '\u{11}' | '\u{16}' | '\u{1D}' | '\u{1E}' | '\u{1F}') {
+            continue;
+        }
+        if start < i {
+            tokens.extend(text[start..i].split_word_bounds().map(|w| (false, w)));
+        }
+        let mut end = i + 1;
+        if c == '\u{3}' {
+            //Up to two digits, then optionally a comma and two more digits
+            let mut digits = 0;
+            while digits < 2 && chars.peek().is_some_and(|(_, d)| d.is_ascii_digit()) {
+                end += 1;
+                digits += 1;
+                chars.next();
+            }
+            if digits > 0 && chars.peek().is_some_and(|(_, d)| *d == ',') {
+                let after = &text[end + 1..];
+                let bg_digits = after.chars().take(2).take_while(|d| d.is_ascii_digit()).count();
+                if bg_digits > 0 {
+                    end += 1 + bg_digits;
+                    for _ in 0..=bg_digits {
+                        chars.next();
+                    }
+                }
+            }
+        }
+        tokens.push((true, &text[i..end]));
+        start = end;
+    }
+    if start < text.len() {
+        tokens.extend(text[start..].split_word_bounds().map(|w| (false, w)));
+    }
+    tokens
+}
+
+// Split a message into pieces of at most max_bytes, breaking between words where possible
+// and reopening the active bold/colour codes at the start of every continuation piece
+pub fn split_message(text: &str, max_bytes: usize) -> Vec<String> {
+    let max_bytes = max_bytes.max(16);
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    let mut state = FormatState::default();
+    // Something other than reopened codes has been written to current
+    let mut has_text = false;
+
+    for (is_code, token) in format_tokens(text) {
+        if is_code {
+            if current.len() + token.len() > max_bytes {
+                pieces.push(std::mem::take(&mut current));
+                current = state.codes();
+                has_text = false;
+            }
+            current.push_str(token);
+            state.apply(token);
+            continue;
+        }
+        if current.len() + token.len() <= max_bytes {
+            current.push_str(token);
+            has_text = true;
+            continue;
+        }
+        //The space a piece is broken at is dropped
+        if has_text {
+            current.truncate(current.trim_end_matches(' ').len());
+            pieces.push(std::mem::take(&mut current));
+            current = state.codes();
+            has_text = false;
+            if token.trim().is_empty() {
+                continue;
+            }
+            if current.len() + token.len() <= max_bytes {
+                current.push_str(token);
+                has_text = true;
+                continue;
+            }
+        }
+        //Word longer than a whole piece, break it between graphemes
+        for grapheme in token.graphemes(true) {
+            if has_text && current.len() + grapheme.len() > max_bytes {
+                pieces.push(std::mem::take(&mut current));
+                current = state.codes();
+            }
+            current.push_str(grapheme);
+            has_text = true;
+        }
+    }
+    if has_text || pieces.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
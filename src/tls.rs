@@ -0,0 +1,44 @@
+// tls.rs
+use std::io;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+fn client_config(ca: Option<&str>, client_cert: Option<&str>) -> io::Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider).with_safe_default_protocol_versions().map_err(io::Error::other)?;
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = ca {
+        //Pem file with certificates trusted besides the usual roots, for a server with its own CA
+        let pem = std::fs::read(path)?;
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            roots.add(cert.map_err(io::Error::other)?).map_err(io::Error::other)?;
+        }
+    }
+    let builder = builder.with_root_certificates(roots);
+
+    match client_cert {
+        Some(path) => {
+            //Pem file holding both the certificate chain and the private key, used for SASL EXTERNAL
+            let pem = std::fs::read(path)?;
+            let certs = CertificateDer::pem_slice_iter(&pem).collect::<Result<Vec<_>, _>>().map_err(io::Error::other)?;
+            let key = PrivateKeyDer::from_pem_slice(&pem).map_err(io::Error::other)?;
+            builder.with_client_auth_cert(certs, key).map_err(io::Error::other)
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+pub async fn connect(stream: TcpStream, host: &str, ca: Option<&str>, client_cert: Option<&str>) -> io::Result<TlsStream<TcpStream>> {
+    let config = client_config(ca, client_cert)?;
+    let server_name = ServerName::try_from(host.to_string()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let connector = TlsConnector::from(Arc::new(config));
+    connector.connect(server_name, stream).await
+}
@@ -1,13 +1,14 @@
 // tui.rs
 use crate::app::App;
 use crate::app::Popup;
+use crate::app::{Activity, ChannelData};
 use crate::textstyle;
 use ratatui::{Frame, widgets::{Block, Borders, Paragraph, Tabs, RenderDirection, Sparkline}};
 use ratatui::widgets::{List, Clear, Wrap, BorderType, ListItem};
 use ratatui::style::{Color, Style, Modifier};
 use ratatui::text::{Line, Span};
 use ratatui::layout::Flex;
-use ratatui::prelude::{Rect, Layout, Position, Alignment}; 
+use ratatui::prelude::{Rect, Layout, Position}; 
 use ratatui::prelude::Constraint::{Percentage, Fill, Min, Length};
 use ratatui::symbols::bar::Set;
 use strum_macros::{FromRepr, EnumIter, Display};
@@ -23,6 +24,8 @@ enum SelectedTab {
     Tab2,
     #[strum(to_string = " F3-Overview ")]
     Tab3,
+    #[strum(to_string = " F4-Mentions ")]
+    Tab4,
 }
 
 impl SelectedTab {
@@ -65,14 +68,29 @@ pub fn draw(frame: &mut Frame, app: &mut App) {
     let threshold = app.w as usize - 12 - UnicodeWidthStr::width(app.active_channel.as_str()) - UnicodeWidthStr::width(app.active_nick.as_str()) - UnicodeWidthStr::width(app.active_server.as_str());
     let binding = app.prompt.clone();
     let (visible_prompt, cursor_offset_x) = textstyle::visible_prompt_and_cursor_offset(&binding, threshold, app);
-    let input_title: Vec<Span> = itertools::Itertools::intersperse(app.input_mode.clone().into_iter(), Span::from("|"),).collect();
+    let mut input_title: Vec<Span> = itertools::Itertools::intersperse(app.input_mode.clone().into_iter(), Span::from("|"),).collect();
+    //Ctrl-F mode, the prompt is the search pattern
+    if app.search.typing {
+        let state = if app.search.regex.is_some() || app.prompt.is_empty() { "Search" } else { "Search (invalid)" };
+        input_title = vec![Span::from(state)];
+    }
     let input = Paragraph::new(Line::from(visible_prompt)).block(Block::bordered().title(Line::from(input_title.clone()).right_aligned()).border_type(BorderType::default()).border_style(Style::new().fg(Color::Rgb(color_map.fg. 0,color_map.fg.1, color_map.fg.2))).borders(Borders::TOP | Borders::RIGHT | Borders::BOTTOM));
 
     let vertical_layout = Layout::vertical([Length(1), Min(0), Length(3)]);
     let [info_bar, stream_area, input_horizontal_area] = vertical_layout.areas(frame.area()); 
 
-    let horizontal_info_layout = Layout::horizontal([Fill(1), Length(38), Fill(1)]);
-    let [ spark_area, tab_area, spark2_area] = horizontal_info_layout.areas(info_bar);
+    //Round trip time of our own PING and lines waiting for the flood limit on the active server
+    let mut lag_text = String::new();
+    if let Some(server) = app.server_list.get(&app.active_server) {
+        if server.send_queue > 0 {
+            lag_text.push_str(&format!(" queue {} ", server.send_queue));
+        }
+        if let Some(lag) = server.lag {
+            lag_text.push_str(&format!(" lag {:.2}s ", lag.as_secs_f64()));
+        }
+    }
+    let horizontal_info_layout = Layout::horizontal([Fill(1), Length(53), Fill(1), Length(lag_text.len() as u16)]);
+    let [ spark_area, tab_area, spark2_area, lag_area] = horizontal_info_layout.areas(info_bar);
     let horizontal_input_layout = Layout::horizontal([Length(10+app.active_channel.len().try_into().unwrap_or(0)+app.active_nick.len().try_into().unwrap_or(0)+app.active_server.len().try_into().unwrap_or(0)), Fill(1)]);
     let [nick_area, input_area] = horizontal_input_layout.areas(input_horizontal_area);
 
@@ -85,15 +103,19 @@ pub fn draw(frame: &mut Frame, app: &mut App) {
     frame.render_widget(input, input_area);
     frame.render_widget(sparkline, spark_area);
     frame.render_widget(sparkline_rev, spark2_area);
+    frame.render_widget(Paragraph::new(lag_text).style(Style::new().fg(Color::Rgb(color_map.txt.0, color_map.txt.1, color_map.txt.2))), lag_area);
     frame.render_widget(tabs, tab_area);
     frame.render_widget(nick_layout, nick_area);
 
-    if app.split.0 {
-        render_split_main(frame, app, &color_map, stream_area);
-    } else {
-        render_main(frame, app, &color_map, stream_area);
+    //Sidebar on the left when it is on and fits
+    let sidebar_layout = Layout::horizontal([Length(app.sidebar_columns()), Fill(1)]);
+    let [sidebar_area, stream_area] = sidebar_layout.areas(stream_area);
+    if app.sidebar_columns() > 0 {
+        render_sidebar(frame, app, &color_map, sidebar_area);
     }
 
+    render_main(frame, app, &color_map, stream_area);
+
     draw_popup(frame, app, &color_map);
 
     frame.set_cursor_position(Position::new(input_area.x + cursor_offset_x as u16, input_area.y + 1));
@@ -107,6 +129,8 @@ fn draw_popup(frame: &mut Frame, app: &mut App, colors: &Colors) {
         Popup::Help    => render_help_pop(frame, colors),
         Popup::User    => render_user_pop(frame, app, colors),
         Popup::Channel => render_chan_pop(frame, app, colors),
+        Popup::Search  => render_search_pop(frame, app, colors),
+        Popup::Mentions => render_mentions_pop(frame, app, colors),
     }
 }
 
@@ -150,20 +174,32 @@ fn render_help_pop(frame: &mut Frame, c: &Colors) {
 fn render_user_pop(frame: &mut Frame, app: &mut App, c: &Colors) {
     let mut user_lines: Vec<Line> = Vec::new();
     let mut user_length: usize = 0;
+    let mut op_count: usize = 0;
     if let Some(server) = app.server_list.get(&app.active_server) {
-        if let Some(channel) = server.channels.get(&app.active_channel) {
-            user_length = channel.user_list.len();
-            for user in &channel.user_list {
-                user_lines.push(Line::from(Span::from(user).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2)))));
+        if let Some(channel) = server.channel(&app.active_channel) {
+            user_length = channel.members.len();
+            //Ops are everyone ranked @ or higher, ~ & @
+            let op_rank = server.isupport.prefix.iter().position(|(mode, _)| *mode == 'o').unwrap_or(0);
+            for member in channel.sorted_members(&server.isupport) {
+                if member.rank(&server.isupport) <= op_rank {
+                    op_count += 1;
+                }
+                let symbol = member.symbol(&server.isupport).map_or(" ".to_string(), |s| s.to_string());
+                let mut style = Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2));
+                if member.away {
+                    style = style.add_modifier(Modifier::DIM);
+                }
+                user_lines.push(Line::from(Span::from(symbol + &member.nick).style(style)));
             }
         }
     }
+    let count_title = format!("{} users, {} ops", user_length, op_count);
 
     let user_block = if user_length > ((app.h as usize * 70) / 100) - 1 {
         let slice = &user_lines[app.menu_pos..((app.h as usize * 70) / 100) - 1 + app.menu_pos];
-        Paragraph::new(slice.to_vec()).block(Block::bordered().style(Style::default().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).bg(Color::Rgb(c.bg.0 - 10, c.bg.1 - 10, c.bg.2 - 10))).title(Line::from(user_length.to_string()).right_aligned()).title(Line::from("Users").left_aligned()))
+        Paragraph::new(slice.to_vec()).block(Block::bordered().style(Style::default().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).bg(Color::Rgb(c.bg.0 - 10, c.bg.1 - 10, c.bg.2 - 10))).title(Line::from(count_title.clone()).right_aligned()).title(Line::from("Users").left_aligned()))
     } else {
-        Paragraph::new(user_lines).block(Block::bordered().style(Style::default().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).bg(Color::Rgb(c.bg.0 - 10, c.bg.1 - 10, c.bg.2 - 10))).title(Line::from(user_length.to_string()).right_aligned()).title(Line::from("Users").left_aligned()))
+        Paragraph::new(user_lines).block(Block::bordered().style(Style::default().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).bg(Color::Rgb(c.bg.0 - 10, c.bg.1 - 10, c.bg.2 - 10))).title(Line::from(count_title.clone()).right_aligned()).title(Line::from("Users").left_aligned()))
     };
 
     let user_popup_area = pop_area(frame.area(), 30, 60);
@@ -173,26 +209,35 @@ fn render_user_pop(frame: &mut Frame, app: &mut App, c: &Colors) {
 
 fn render_chan_pop(frame: &mut Frame, app: &mut App, c: &Colors) {
     let mut channel_lines: Vec<Line> = Vec::new();
-    let mut index = 0;
     let mut state_index = 0;
-
-    for (outer_key, inner_map) in &app.server_list {
-        channel_lines.push(Line::from(outer_key.to_owned()).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2))));
-        state_index += 1;
-        for (inner_key, data) in &inner_map.channels {
-            if data.notification == true && inner_key != "Status" {
-                channel_lines.push(Line::from(vec![Span::from(format!("[!] ")).style(Style::new().fg(Color::Rgb(c.notif.0, c.notif.1, c.notif.2))), Span::from(format!("{}: {}", index, inner_key)).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2)))]));
-            } else {
-                channel_lines.push(Line::from(format!("    {}: {}", index, inner_key)).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2))));
-            }
-
-            if *inner_key == app.active_channel && *outer_key == app.active_server {
-                app.channel_state.select(Some(state_index));
-            }
-
-            index += 1;
+    let mut last_server = None;
+
+    //Same numbers as /swap and Alt-0..9
+    for (index, (outer_key, inner_key)) in app.buffer_list().into_iter().enumerate() {
+        let Some(inner_map) = app.server_list.get(&outer_key) else {
+            continue;
+        };
+        if last_server.as_ref() != Some(&outer_key) {
+            //NETWORK name from ISUPPORT when the server sent one
+            let server_name = inner_map.isupport.network.clone().unwrap_or(outer_key.to_owned());
+            channel_lines.push(Line::from(server_name).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2))));
             state_index += 1;
+            last_server = Some(outer_key.clone());
+        }
+        let Some(data) = inner_map.channel(&inner_key) else {
+            continue;
+        };
+        if data.activity != Activity::None && inner_key.as_str() != "Status" {
+            channel_lines.push(Line::from(vec![activity_span(data, c), Span::from(format!(" {}: {}", index, inner_key)).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2)))]));
+        } else {
+            channel_lines.push(Line::from(format!("    {}: {}", index, inner_key)).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2))));
+        }
+
+        if inner_map.key(&inner_key).matches(&app.active_channel) && outer_key == app.active_server {
+            app.channel_state.select(Some(state_index));
         }
+
+        state_index += 1;
     }
 
     let channel_block = List::new(channel_lines).highlight_symbol("🮥 ").highlight_style(Style::default().fg(Color::Rgb(c.highlight.0, c.highlight.1, c.highlight.2))).block(Block::bordered().style(Style::default().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).bg(Color::Rgb(c.bg.0 - 10, c.bg.1 - 10, c.bg.2 - 10))).title(Line::from("Server/Channels").centered()));
@@ -201,64 +246,185 @@ fn render_chan_pop(frame: &mut Frame, app: &mut App, c: &Colors) {
     frame.render_stateful_widget(channel_block, channel_popup_area, &mut app.channel_state);
 }
 
-fn render_main(frame: &mut Frame, app: &mut App, colors: &Colors, area: Rect) {
-    let mut lines: Vec<Line> = textstyle::chat_style(app, app.active_server.clone(), app.active_channel.clone());
+// [-] for events only, otherwise the unread count, in the colour of the level
+fn activity_span(data: &ChannelData, c: &Colors) -> Span<'static> {
+    let text = if data.activity == Activity::Events { "[-]".to_string() } else { format!("[{}]", data.unread) };
+    Span::from(text).style(activity_style(data.activity, c))
+}
 
-    if lines.len() > app.h as usize - 6 {
-        if let Some(server) = app.server_list.get(&app.active_server) {
-            if let Some(channel) = server.channels.get(&app.active_channel) {
-                let chat_slice_start = lines.len().saturating_sub(app.h as usize - 6).saturating_sub(channel.chat_pos);
-                lines = lines[chat_slice_start..].to_vec();
-            }
+fn activity_style(activity: Activity, c: &Colors) -> Style {
+    match activity {
+        Activity::Private => Style::new().fg(Color::Rgb(c.notif.0, c.notif.1, c.notif.2)).add_modifier(Modifier::BOLD),
+        Activity::Highlight => Style::new().fg(Color::Rgb(c.highlight.0, c.highlight.1, c.highlight.2)).add_modifier(Modifier::BOLD),
+        Activity::Messages => Style::new().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)),
+        Activity::Events => Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2)).add_modifier(Modifier::DIM),
+        Activity::None => Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2)),
+    }
+}
+
+// Buffer tree left of the chat, numbered like the overview and coloured by activity
+fn render_sidebar(frame: &mut Frame, app: &mut App, c: &Colors, area: Rect) {
+    let mut sidebar_lines: Vec<ListItem> = Vec::new();
+    let mut selected = None;
+    let buffers = app.buffer_list();
+
+    for (index, (server_id, channel_id)) in buffers.iter().enumerate() {
+        let Some(server) = app.server_list.get(server_id) else {
+            continue;
+        };
+        if index == 0 || buffers[index - 1].0 != *server_id {
+            sidebar_lines.push(ListItem::new(Line::from(app.network_name(server_id)).style(Style::new().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).add_modifier(Modifier::BOLD))));
+        }
+        let Some(data) = server.channel(channel_id) else {
+            continue;
+        };
+        let last_of_server = buffers.get(index + 1).is_none_or(|(next, _)| next != server_id);
+        let branch = if last_of_server { "└" } else { "├" };
+        let mut spans = vec![
+            Span::from(format!("{}{} ", branch, index)).style(Style::new().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).add_modifier(Modifier::DIM)),
+            Span::from(channel_id.clone()).style(activity_style(data.activity, c)),
+        ];
+        if data.unread > 0 {
+            spans.push(Span::from(format!(" {}", data.unread)).style(activity_style(data.activity, c)));
+        }
+        if *server_id == app.active_server && server.key(channel_id).matches(&app.active_channel) {
+            selected = Some(sidebar_lines.len());
         }
+        sidebar_lines.push(ListItem::new(Line::from(spans)));
     }
 
-    let message_layout = List::new(lines).block(Block::bordered().border_style(Style::new().fg(Color::Rgb(colors.fg. 0,colors.fg.1, colors.fg.2))));
-    frame.render_widget(message_layout, area);
+    app.sidebar_state.select(selected);
+    let sidebar_block = List::new(sidebar_lines).highlight_style(Style::default().add_modifier(Modifier::REVERSED)).block(Block::bordered().border_style(Style::new().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2))));
+    frame.render_stateful_widget(sidebar_block, area, &mut app.sidebar_state);
 }
 
-fn render_split_main(frame: &mut Frame, app: &mut App, colors: &Colors, area: Rect) {
-    let (_, server_left, left, server_right, right) = app.split.clone();
-    let split_chat = Layout::horizontal([Fill(1), Fill(1)]);
-    let [split_left, split_right] = split_chat.areas(area);
+fn render_search_pop(frame: &mut Frame, app: &mut App, c: &Colors) {
+    //server channel time sender: text, newest first
+    let search_lines: Vec<ListItem> = app.search.hits.iter().map(|hit| {
+        let snippet: String = hit.text.chars().filter(|ch| !ch.is_control()).collect();
+        ListItem::new(Line::from(vec![
+            Span::from(format!("{} {} ", app.network_name(&hit.server), hit.channel)).style(Style::new().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2))),
+            Span::from(format!("{} ", hit.time.format("%Y-%m-%d %H:%M"))).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2)).add_modifier(Modifier::DIM)),
+            Span::from(format!("{}: {}", hit.sender, snippet)).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2))),
+        ]))
+    }).collect();
+    let count_title = format!("{} matches", search_lines.len());
+
+    let search_block = List::new(search_lines).highlight_symbol("🮥 ").highlight_style(Style::default().fg(Color::Rgb(c.highlight.0, c.highlight.1, c.highlight.2))).block(Block::bordered().style(Style::default().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).bg(Color::Rgb(c.bg.0 - 10, c.bg.1 - 10, c.bg.2 - 10))).title(Line::from("Search").left_aligned()).title(Line::from(count_title).right_aligned()));
+    let search_popup_area = pop_area(frame.area(), 80, 70);
+    frame.render_widget(Clear, search_popup_area); //this clears out the background
+    frame.render_stateful_widget(search_block, search_popup_area, &mut app.search_state);
+}
 
-    let mut lines_left: Vec<Line> = textstyle::chat_style(app, server_left.clone(), left.clone());
+fn render_mentions_pop(frame: &mut Frame, app: &mut App, c: &Colors) {
+    //server channel time sender: text, newest first
+    let mention_lines: Vec<Line> = app.mentions.iter().rev().map(|mention| {
+        let snippet: String = mention.text.chars().filter(|ch| !ch.is_control()).collect();
+        let server_name = app.network_name(&mention.server);
+        Line::from(vec![
+            Span::from(format!("{} {} ", server_name, mention.channel)).style(Style::new().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2))),
+            Span::from(format!("{} ", mention.time.format("%Y-%m-%d %H:%M"))).style(Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2)).add_modifier(Modifier::DIM)),
+            Span::from(format!("{}: {}", mention.sender, snippet)).style(Style::new().fg(Color::Rgb(c.highlight.0, c.highlight.1, c.highlight.2))),
+        ])
+    }).collect();
+    let count_title = format!("{} mentions", mention_lines.len());
+
+    let mention_block = Paragraph::new(mention_lines).block(Block::bordered().style(Style::default().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2)).bg(Color::Rgb(c.bg.0 - 10, c.bg.1 - 10, c.bg.2 - 10))).title(Line::from("Mentions").left_aligned()).title(Line::from(count_title).right_aligned()));
+    let mention_popup_area = pop_area(frame.area(), 80, 70);
+    frame.render_widget(Clear, mention_popup_area); //this clears out the background
+    frame.render_widget(mention_block, mention_popup_area);
+}
 
-    if lines_left.len() > app.h as usize - 6 {
-        if let Some(server) = app.server_list.get(&server_left) {
-            if let Some(channel) = server.channels.get(&left) {
-                let chat_slice_start = lines_left.len().saturating_sub(app.h as usize - 6).saturating_sub(channel.chat_pos);
-                lines_left = lines_left[chat_slice_start..].to_vec();
-            }
-        }
+fn render_main(frame: &mut Frame, app: &mut App, colors: &Colors, area: Rect) {
+    let nick_layout = Layout::horizontal([Fill(1), Length(app.nicklist_columns())]);
+    let [area, nick_area] = nick_layout.areas(area);
+    if app.nicklist_columns() > 0 {
+        render_nicklist(frame, app, colors, nick_area);
     }
 
-    if app.active_server == server_left && app.active_channel == left {
-        let message_layout_left = List::new(lines_left).block(Block::bordered().title_top(left).border_style(Style::new().fg(Color::Rgb(colors.highlight. 0,colors.highlight.1, colors.highlight.2))));
-        frame.render_widget(message_layout_left, split_left);
-    } else {
-        let message_layout_left = List::new(lines_left).block(Block::bordered().title_top(left).border_style(Style::new().fg(Color::Rgb(colors.fg. 0,colors.fg.1, colors.fg.2))));
-        frame.render_widget(message_layout_left, split_left);
+    //Every pane of the layout, the same areas the wrap width is worked out from
+    let split = app.layout.is_split();
+    let panes: Vec<(String, String)> = app.layout.panes().iter().map(|(s, c)| (s.to_string(), c.to_string())).collect();
+    let rects = app.layout.rects(area);
+    for (index, ((server_id, channel_id), pane_area)) in panes.into_iter().zip(rects).enumerate() {
+        render_pane(frame, app, colors, &server_id, &channel_id, pane_area, split.then_some(index));
     }
+}
 
+// One chat block, with more than one pane it is numbered for /focus and the focused one has the highlight border
+fn render_pane(frame: &mut Frame, app: &mut App, colors: &Colors, server_id: &str, channel_id: &str, area: Rect, number: Option<usize>) {
+    let mut lines: Vec<Line> = textstyle::chat_style(app, server_id.to_string(), channel_id.to_string());
+    //The topic line comes off the pane, the rest minus the borders is chat
+    let chat_rows = app.chat_rows(server_id, channel_id, area);
+    let (area, _) = render_topic(frame, app, colors, server_id, channel_id, area);
+
+    if lines.len() > chat_rows {
+        if let Some(server) = app.server_list.get(server_id) {
+            if let Some(channel) = server.channel(channel_id) {
+                let chat_slice_start = lines.len().saturating_sub(chat_rows).saturating_sub(channel.chat_pos);
+                lines = lines[chat_slice_start..].to_vec();
+            }
+        }
+    }
 
-    let mut lines_right: Vec<Line> = textstyle::chat_style(app, server_right.clone(), right.clone());
+    let modes = channel_modes(app, server_id, channel_id);
+    let message_layout = match number {
+        None => List::new(lines).block(Block::bordered().title_top(Line::from(modes).right_aligned()).border_style(Style::new().fg(Color::Rgb(colors.fg. 0,colors.fg.1, colors.fg.2)))),
+        Some(index) => {
+            let title = format!("{} {} {}", index, channel_id, modes);
+            let focused = index == app.layout.focus;
+            let border = if focused { colors.highlight } else { colors.fg };
+            List::new(lines).block(Block::bordered().title_top(title.trim_end().to_string()).border_style(Style::new().fg(Color::Rgb(border.0, border.1, border.2))))
+        }
+    };
+    frame.render_widget(message_layout, area);
+}
 
-    if lines_right.len() > app.h as usize - 6 {
-        if let Some(server) = app.server_list.get(&server_right) {
-            if let Some(channel) = server.channels.get(&right) {
-                let chat_slice_start = lines_right.len().saturating_sub(app.h as usize - 6).saturating_sub(channel.chat_pos);
-                lines_right = lines_right[chat_slice_start..].to_vec();
+// Members of the active channel with their mode prefix, away users dimmed, scrolled by nicklist_pos
+fn render_nicklist(frame: &mut Frame, app: &mut App, c: &Colors, area: Rect) {
+    let mut nick_lines: Vec<Line> = Vec::new();
+    if let Some(server) = app.server_list.get(&app.active_server) {
+        if let Some(channel) = server.channel(&app.active_channel) {
+            for member in channel.sorted_members(&server.isupport) {
+                let symbol = member.symbol(&server.isupport).map_or(" ".to_string(), |s| s.to_string());
+                let mut style = Style::new().fg(Color::Rgb(c.txt.0, c.txt.1, c.txt.2));
+                if member.away {
+                    style = style.add_modifier(Modifier::DIM);
+                }
+                nick_lines.push(Line::from(Span::from(symbol + &member.nick).style(style)));
             }
         }
     }
+    let count_title = nick_lines.len().to_string();
+    let rows = area.height.saturating_sub(2) as usize;
+    app.nicklist_pos = app.nicklist_pos.min(nick_lines.len().saturating_sub(rows));
+    let visible: Vec<Line> = nick_lines.into_iter().skip(app.nicklist_pos).take(rows).collect();
 
-    if app.active_server == server_right && app.active_channel == right{
-        let message_layout_right = List::new(lines_right).block(Block::bordered().title_top(right).title_alignment(Alignment::Right).border_style(Style::new().fg(Color::Rgb(colors.highlight. 0,colors.highlight.1, colors.highlight.2))));
-        frame.render_widget(message_layout_right, split_right);
-    } else {
-        let message_layout_right = List::new(lines_right).block(Block::bordered().title_top(right).title_alignment(Alignment::Right).border_style(Style::new().fg(Color::Rgb(colors.fg. 0,colors.fg.1, colors.fg.2))));
-        frame.render_widget(message_layout_right, split_right);
+    let nick_block = Paragraph::new(visible).block(Block::bordered().title(Line::from(count_title).right_aligned()).border_style(Style::new().fg(Color::Rgb(c.fg.0, c.fg.1, c.fg.2))));
+    frame.render_widget(nick_block, area);
+}
+
+// Current modes of a channel for its chat block title
+fn channel_modes(app: &App, server_id: &str, channel_id: &str) -> String {
+    match app.server_list.get(server_id).and_then(|s| s.channel(channel_id)) {
+        Some(channel) if channel.parted => "(parted)".to_string(),
+        Some(channel) => channel.mode_string(),
+        None => String::new(),
+    }
+}
+
+// Topic line above the chat when the channel has a topic, returns the area left for the chat and the rows used
+fn render_topic(frame: &mut Frame, app: &App, colors: &Colors, server_id: &str, channel_id: &str, area: Rect) -> (Rect, usize) {
+    let topic = app.server_list.get(server_id).and_then(|s| s.channel(channel_id)).and_then(|c| c.topic.clone());
+    match topic {
+        Some(topic) => {
+            let [topic_area, chat_area] = Layout::vertical([Length(1), Min(0)]).areas(area);
+            let text: String = topic.chars().filter(|c| !c.is_control()).collect();
+            let topic_line = Paragraph::new(Line::from(format!(" {}", text))).style(Style::new().fg(Color::Rgb(colors.txt.0, colors.txt.1, colors.txt.2)).bg(Color::Rgb(colors.bg.0.saturating_sub(10), colors.bg.1.saturating_sub(10), colors.bg.2.saturating_sub(10))));
+            frame.render_widget(topic_line, topic_area);
+            (chat_area, 1)
+        }
+        None => (area, 0),
     }
 }
 
@@ -354,10 +520,18 @@ fn help_lines() -> Vec<Line<'static>> {
             Span::styled("/connect ip              ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": To connect to a server", Style::default()),
         ]),
+        Line::from(vec![
+            Span::styled("/connect -tls ip         ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To connect to a server using TLS", Style::default()),
+        ]),
         Line::from(vec![
             Span::styled("/disconnect server       ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": To disconnect from a server", Style::default()),
         ]),
+        Line::from(vec![
+            Span::styled("/reconnect               ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To reconnect to the current server", Style::default()),
+        ]),
         Line::from(vec![
             Span::styled("/Join #Channel           ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": To join a chat channel", Style::default()),
@@ -366,6 +540,34 @@ fn help_lines() -> Vec<Line<'static>> {
             Span::styled("/part #channel           ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": To leave a chat channel", Style::default()),
         ]),
+        Line::from(vec![
+            Span::styled("/mode +modes params      ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To set modes on the current channel", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/op /deop nick           ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To give or take operator status", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/voice nick              ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To give voice", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/ban /unban nick|mask    ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To ban or unban in the current channel", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/kick nick reason        ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To kick from the current channel", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/me text                 ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To send an action", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/ctcp nick command       ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": To send a CTCP query (VERSION, PING, TIME)", Style::default()),
+        ]),
         Line::from(vec![
             Span::styled("/'command'               ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": To use a command", Style::default()),
@@ -384,7 +586,7 @@ fn help_lines() -> Vec<Line<'static>> {
         ]),
         Line::from(vec![
             Span::styled("message styling Reset    ", Style::default().add_modifier(Modifier::BOLD)),
-            Span::styled(": ctrl + 'n'", Style::default()),
+            Span::styled(": ctrl + 'o'", Style::default()),
         ]),
         Line::from(vec![
             Span::styled("message styling color    ", Style::default().add_modifier(Modifier::BOLD)),
@@ -398,6 +600,22 @@ fn help_lines() -> Vec<Line<'static>> {
             Span::styled("'PgUp' or 'PgDown'       ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": Cycle channel chat history", Style::default()),
         ]),
+        Line::from(vec![
+            Span::styled("/search regex            ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Search all buffers, Up/Down and Enter to go to a match", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("ctrl + 'f'               ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Search this buffer, Enter/Up older, Down newer, Esc to stop", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("alt + 'a'                ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Jump to the buffer with the most activity", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("F4                       ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Recent mentions of every server, F4 to close", Style::default()),
+        ]),
         Line::from(vec![
             Span::styled("/list                    ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": To list channels, Esc to close window", Style::default()),
@@ -414,13 +632,49 @@ fn help_lines() -> Vec<Line<'static>> {
             Span::styled("/swap 'number'           ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": Swap active channel", Style::default()),
         ]),
+        Line::from(vec![
+            Span::styled("alt + '0'..'9'           ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Swap to channel number", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("ctrl + 'n' / 'p'         ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Next/previous channel, also alt + Right/Left", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/sidebar 'width'         ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Toggle the channel sidebar, or resize it", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/nicklist 'width'        ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Toggle the nick list, alt + Up/Down scrolls it", Style::default()),
+        ]),
         Line::from(vec![
             Span::styled("/split 'number'-'number' ", Style::default().add_modifier(Modifier::BOLD)),
             Span::styled(": Split screen view", Style::default()),
         ]),
+        Line::from(vec![
+            Span::styled("/vsplit /hsplit 'number' ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Split the active pane side by side or stacked", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/close                   ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Close the active pane", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/resize +'n' or -'n'     ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Grow or shrink the active pane by percent", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/focus 'number'          ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Make a pane active", Style::default()),
+        ]),
+        Line::from(vec![
+            Span::styled("/layout save 'name'      ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(": Save the panes, /layout 'name' restores them", Style::default()),
+        ]),
         Line::from(vec![
             Span::styled("Tab                      ", Style::default().add_modifier(Modifier::BOLD)),
-            Span::styled(": Switch active channel in split view", Style::default()),
+            Span::styled(": Switch active pane in split view", Style::default()),
         ]),
         Line::from(vec![
             Span::styled("Esc                      ", Style::default().add_modifier(Modifier::BOLD)),